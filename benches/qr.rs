@@ -86,5 +86,47 @@ fn bench_fastqr_qrcode(c: &mut Criterion) {
     }
 }
 
-criterion_group!(benches, bench_fastqr_qrcode);
+/// Error correction for all 81 blocks of V40-H, 15 or 16 data codewords + 30 ec each
+fn bench_reed_solomon(c: &mut Criterion) {
+    use fuqr::constants::GEN_POLYNOMIALS;
+    use fuqr::error_correction::{remainder, Encoder};
+
+    let data: Vec<u8> = (0..1276u32).map(|i| (i * 31 + 7) as u8).collect();
+    let blocks: Vec<&[u8]> = (0..81usize)
+        .map(|i| {
+            // 20 blocks of 15, then 61 blocks of 16
+            let start = i * 15 + i.saturating_sub(20);
+            let len = if i < 20 { 15 } else { 16 };
+            &data[start..start + len]
+        })
+        .collect();
+
+    let mut group = c.benchmark_group("V40H RS");
+    group.measurement_time(Duration::from_secs(10));
+    group.sample_size(200);
+
+    group.bench_function("Encoder", |b| {
+        let encoder = Encoder::get(30);
+        let mut ec_codewords = [0; 30];
+        b.iter(|| {
+            for block in blocks.iter() {
+                encoder.encode(black_box(block), &mut ec_codewords);
+                black_box(&ec_codewords);
+            }
+        })
+    });
+
+    group.bench_function("remainder", |b| {
+        let divisor = &GEN_POLYNOMIALS[30][..30];
+        b.iter(|| {
+            for block in blocks.iter() {
+                black_box(remainder(black_box(block), divisor));
+            }
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_fastqr_qrcode, bench_reed_solomon);
 criterion_main!(benches);
@@ -4,6 +4,7 @@ use crate::{
     constants::{NUM_BLOCKS, NUM_DATA_MODULES, NUM_EC_CODEWORDS},
     matrix::{Matrix, Module},
     qr_code::{Mask, Mode, Version, ECL},
+    template::Template,
 };
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -54,26 +55,20 @@ pub struct BitInfo {
 
 impl BitInfo {
     pub fn new(mode: Mode, version: Version, ecl: ECL, mask: Mask) -> Self {
+        let template = Template::get(version);
         let mut bit_info = BitInfo {
-            matrix: Matrix::new(
-                version,
-                Info {
-                    module: Module(0),
-                    block: 0,
-                    bit: 0,
-                },
-            ),
+            matrix: Matrix {
+                value: template.matrix.value.iter().map(|&m| m.into()).collect(),
+                width: template.matrix.width,
+                height: template.matrix.height,
+            },
             mode,
             version,
             ecl,
             mask,
         };
 
-        bit_info.matrix.set_finder();
-        bit_info.matrix.set_alignment();
-        bit_info.matrix.set_timing();
         bit_info.matrix.set_format(bit_info.ecl, mask);
-        bit_info.matrix.set_version();
 
         let modules = NUM_DATA_MODULES[bit_info.version.0] as usize;
         let codewords = modules / 8;
@@ -95,11 +90,11 @@ impl BitInfo {
         let mut block = 0;
         let mut bit = 0;
 
-        bit_info.matrix.set_data(|| {
+        let mut next_info = || {
             let val = Info {
                 module: match i {
                     j if j < data_end => Info::DATA,
-                    j if j < ecc_end * 8 => Info::EC,
+                    j if j < ecc_end => Info::EC,
                     _ => Info::REMAINDER,
                 },
                 block,
@@ -141,7 +136,10 @@ impl BitInfo {
             }
 
             val
-        });
+        };
+        for &(x, y) in template.data.iter() {
+            bit_info.matrix.set(x as usize, y as usize, next_info());
+        }
 
         bit_info
     }
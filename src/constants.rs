@@ -15,6 +15,112 @@ pub const GEN_POLYNOMIALS: [[u8; 30]; 31] = gen_polynomials();
 pub const VERSION_INFO: [usize; 41] = version_info();
 pub const FORMAT_INFO: [[u32; 8]; 4] = format_info();
 
+/// Data bits for Micro QR M1 - M4 by ECL, 0 if ECL isn't supported.
+/// M1 only detects errors, which is listed as Low.
+/// M1 and M3 end with a 4 bit data codeword, so these aren't multiples of 8.
+pub const MICRO_DATA_BITS: [[u16; 4]; 5] = [
+    [0, 0, 0, 0],
+    [20, 0, 0, 0],
+    [40, 32, 0, 0],
+    [84, 68, 0, 0],
+    [128, 112, 80, 0],
+];
+pub const MICRO_EC_CODEWORDS: [[u16; 4]; 5] = [
+    [0, 0, 0, 0],
+    [2, 0, 0, 0],
+    [5, 6, 0, 0],
+    [6, 8, 0, 0],
+    [8, 10, 14, 0],
+];
+/// Micro QR format info encodes version and ECL together as a symbol number
+pub const MICRO_SYMBOL_NUMBER: [[u8; 4]; 5] = [
+    [0, 0, 0, 0],
+    [0, 0, 0, 0],
+    [1, 2, 0, 0],
+    [3, 4, 0, 0],
+    [5, 6, 7, 0],
+];
+pub const MICRO_FORMAT_INFO: [[u32; 4]; 8] = micro_format_info();
+
+/// (width, height) of rMQR versions 1 - 32, aka R7x43 - R17x139
+pub const RMQR_SIZES: [(usize, usize); 33] = [
+    (0, 0),
+    (43, 7),
+    (59, 7),
+    (77, 7),
+    (99, 7),
+    (139, 7),
+    (43, 9),
+    (59, 9),
+    (77, 9),
+    (99, 9),
+    (139, 9),
+    (27, 11),
+    (43, 11),
+    (59, 11),
+    (77, 11),
+    (99, 11),
+    (139, 11),
+    (27, 13),
+    (43, 13),
+    (59, 13),
+    (77, 13),
+    (99, 13),
+    (139, 13),
+    (43, 15),
+    (59, 15),
+    (77, 15),
+    (99, 15),
+    (139, 15),
+    (43, 17),
+    (59, 17),
+    (77, 17),
+    (99, 17),
+    (139, 17),
+];
+pub const RMQR_DATA_MODULES: [u16; 33] = rmqr_data_modules();
+/// rMQR only supports Medium and High, other ECLs are 0
+pub const RMQR_EC_CODEWORDS: [[u16; 4]; 33] = rmqr_ec_codewords();
+pub const RMQR_BLOCKS: [[u8; 4]; 33] = rmqr_blocks();
+/// Char count indicator lengths for Numeric, Alphanumeric, Byte, Kanji
+pub const RMQR_CCI_BITS: [[u8; 4]; 33] = [
+    [0, 0, 0, 0],
+    [4, 3, 3, 2],
+    [5, 5, 4, 3],
+    [6, 5, 5, 4],
+    [7, 6, 5, 5],
+    [7, 6, 6, 5],
+    [5, 5, 4, 3],
+    [6, 5, 5, 4],
+    [7, 6, 5, 5],
+    [7, 6, 6, 5],
+    [8, 7, 6, 6],
+    [4, 4, 3, 2],
+    [6, 5, 5, 4],
+    [7, 6, 5, 5],
+    [7, 6, 6, 5],
+    [8, 7, 6, 6],
+    [8, 7, 7, 6],
+    [5, 5, 4, 3],
+    [6, 6, 5, 5],
+    [7, 6, 6, 5],
+    [7, 7, 6, 6],
+    [8, 7, 7, 6],
+    [8, 8, 7, 7],
+    [7, 6, 6, 5],
+    [7, 7, 6, 5],
+    [8, 7, 7, 6],
+    [8, 7, 7, 6],
+    [9, 8, 7, 7],
+    [7, 6, 6, 5],
+    [8, 7, 6, 6],
+    [8, 7, 7, 6],
+    [8, 8, 7, 6],
+    [9, 8, 8, 7],
+];
+/// Unmasked, indexed by ECL bit (High = 1) followed by 5 bit version indicator (version - 1)
+pub const RMQR_FORMAT_INFO: [u32; 64] = rmqr_format_info();
+
 const fn num_data_modules() -> [u16; 41] {
     let mut table = [0; 41];
 
@@ -225,6 +331,177 @@ const fn format_info() -> [[u32; 8]; 4] {
     array
 }
 
+const fn micro_format_info() -> [[u32; 4]; 8] {
+    let mut array = [[0; 4]; 8];
+
+    let mut symbol_number = 0;
+    while symbol_number < 8 {
+        let mut mask = 0;
+        while mask < 4 {
+            let format = (((symbol_number << 2) | mask) as u32) << 10;
+            let mut dividend = format;
+
+            while dividend >= 0b100_0000_0000 {
+                let mut divisor = 0b101_0011_0111;
+                divisor <<= (32 - dividend.leading_zeros()) - 11;
+
+                dividend ^= divisor;
+            }
+
+            array[symbol_number][mask] = (format | dividend) ^ 0b100_0100_0100_0101;
+            mask += 1;
+        }
+
+        symbol_number += 1;
+    }
+
+    array
+}
+
+const fn rmqr_data_modules() -> [u16; 33] {
+    let mut table = [0; 33];
+
+    let mut version = 1;
+    while version <= 32 {
+        let (width, height) = RMQR_SIZES[version];
+        let mut modules = width * height;
+
+        modules -= 2 * width + 2 * (height - 2); // edge timing + corners
+        modules -= if height == 7 { 7 * 5 } else { 7 * 7 }; // finder + separator
+        modules -= 2 * 18; // 2 format
+        modules -= 4 * 4; // sub finder
+        modules -= if height >= 11 { 2 } else { 1 }; // corner patterns
+
+        let align = match width {
+            27 => 0,
+            43 => 1,
+            59 | 77 => 2,
+            99 => 3,
+            _ => 4,
+        };
+        // 2 alignment + vertical timing
+        modules -= align * (2 * 6 + (height - 6));
+
+        table[version] = modules as u16;
+        version += 1;
+    }
+    table
+}
+
+const fn rmqr_ec_codewords() -> [[u16; 4]; 33] {
+    let medium_high = [
+        [0, 0],
+        [7, 10],
+        [9, 14],
+        [12, 22],
+        [16, 30],
+        [24, 44],
+        [9, 14],
+        [12, 22],
+        [18, 32],
+        [24, 44],
+        [36, 66],
+        [8, 10],
+        [12, 20],
+        [16, 32],
+        [24, 44],
+        [32, 60],
+        [48, 90],
+        [9, 14],
+        [14, 28],
+        [22, 40],
+        [32, 56],
+        [40, 78],
+        [60, 112],
+        [18, 36],
+        [26, 48],
+        [36, 72],
+        [48, 88],
+        [72, 130],
+        [22, 40],
+        [32, 60],
+        [44, 84],
+        [60, 104],
+        [80, 156],
+    ];
+
+    let mut table = [[0; 4]; 33];
+    let mut version = 1;
+    while version <= 32 {
+        table[version][ECL::Medium as usize] = medium_high[version][0];
+        table[version][ECL::High as usize] = medium_high[version][1];
+        version += 1;
+    }
+    table
+}
+
+const fn rmqr_blocks() -> [[u8; 4]; 33] {
+    let medium_high = [
+        [0, 0],
+        [1, 1],
+        [1, 1],
+        [1, 1],
+        [1, 1],
+        [1, 2],
+        [1, 1],
+        [1, 1],
+        [1, 2],
+        [1, 2],
+        [2, 3],
+        [1, 1],
+        [1, 1],
+        [1, 2],
+        [1, 2],
+        [2, 2],
+        [2, 3],
+        [1, 1],
+        [1, 1],
+        [1, 2],
+        [2, 2],
+        [2, 3],
+        [3, 4],
+        [1, 2],
+        [1, 2],
+        [2, 3],
+        [2, 4],
+        [3, 5],
+        [1, 2],
+        [2, 2],
+        [2, 3],
+        [3, 4],
+        [4, 6],
+    ];
+
+    let mut table = [[0; 4]; 33];
+    let mut version = 1;
+    while version <= 32 {
+        table[version][ECL::Medium as usize] = medium_high[version][0];
+        table[version][ECL::High as usize] = medium_high[version][1];
+        version += 1;
+    }
+    table
+}
+
+const fn rmqr_format_info() -> [u32; 64] {
+    let mut array = [0; 64];
+
+    let mut i = 0;
+    while i < 64 {
+        let format = (i as u32) << 12;
+        let mut dividend = format;
+
+        while dividend >= 0b1_0000_0000_0000 {
+            let mut divisor = 0b1_1111_0010_0101;
+            divisor <<= (32 - dividend.leading_zeros()) - 13;
+
+            dividend ^= divisor;
+        }
+        array[i] = format | dividend;
+        i += 1;
+    }
+    array
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -242,5 +519,28 @@ mod tests {
         assert_eq!(FORMAT_INFO[ECL::Medium as usize][Mask::M0 as usize], 0x5412);
         assert_eq!(FORMAT_INFO[ECL::High as usize][Mask::M0 as usize], 0x1689);
         assert_eq!(FORMAT_INFO[ECL::High as usize][Mask::M7 as usize], 0x083B);
+
+        assert_eq!(MICRO_FORMAT_INFO[0][Mask::M0 as usize], 0x4445);
+        assert_eq!(MICRO_FORMAT_INFO[1][Mask::M1 as usize], 0x5099);
+        assert_eq!(MICRO_FORMAT_INFO[7][Mask::M3 as usize], 0x3BBA);
+    }
+
+    #[test]
+    fn rmqr_codewords_work() {
+        // total codewords and remainder bits from ISO 23941
+        assert_eq!(RMQR_DATA_MODULES[1], 13 * 8);
+        assert_eq!(RMQR_DATA_MODULES[2], 21 * 8 + 3);
+        assert_eq!(RMQR_DATA_MODULES[11], 15 * 8 + 2);
+        assert_eq!(RMQR_DATA_MODULES[32], 232 * 8 + 4);
+
+        for version in 1..=32 {
+            let codewords = (RMQR_DATA_MODULES[version] / 8) as usize;
+            for ecl in [ECL::Medium, ECL::High] {
+                let ec = RMQR_EC_CODEWORDS[version][ecl as usize] as usize;
+                let blocks = RMQR_BLOCKS[version][ecl as usize] as usize;
+                assert!(ec < codewords);
+                assert_eq!(ec % blocks, 0);
+            }
+        }
     }
 }
@@ -1,59 +1,180 @@
 use crate::{
-    constants::{NUM_DATA_MODULES, NUM_EC_CODEWORDS},
-    encoding::{encode_alphanumeric, encode_byte, encode_numeric, num_cci_bits},
-    qr_code::{Mode, Version, ECL},
+    constants::{
+        MICRO_DATA_BITS, NUM_DATA_MODULES, NUM_EC_CODEWORDS, RMQR_DATA_MODULES, RMQR_EC_CODEWORDS,
+    },
+    encoding::escape_fnc1,
+    qr_code::{Eci, Fnc1, Mode, Symbol, Version, ECL},
+    segment::{optimal_micro_segments, optimal_rmqr_segments, optimal_segments_bytes, Segment},
+    QrError,
 };
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Data {
     pub bits: BitVec,
+    pub symbol: Symbol,
+    /// Mode of all segments, or Byte if segments use different modes
     pub mode: Mode,
     pub version: Version,
     pub ecl: ECL,
+    pub segments: Vec<Segment>,
 }
 
 impl Data {
+    pub fn builder() -> DataBuilder {
+        DataBuilder::new()
+    }
+
     pub fn new(text: &str, mode: Mode, min_version: Version, min_ecl: ECL) -> Option<Self> {
-        Self::new_verbose(text, mode, min_version, false, min_ecl, false)
+        Self::new_verbose(text, mode, None, min_version, false, min_ecl, false)
     }
 
     pub fn new_verbose(
         text: &str,
         mode: Mode,
+        eci: Option<Eci>,
         min_version: Version,
         strict_version: bool,
         min_ecl: ECL,
         strict_ecl: bool,
     ) -> Option<Self> {
-        let mut bits = 0;
-        bits += 4 + num_cci_bits(min_version, mode);
-        let char_len = text.len();
-        match mode {
-            Mode::Numeric => {
-                bits += (char_len / 3) * 10;
-                match char_len % 3 {
-                    2 => bits += 7,
-                    1 => bits += 4,
-                    _ => (),
-                }
-            }
-            Mode::Alphanumeric => {
-                bits += (char_len / 2) * 11 + (char_len % 2) * 6;
+        Self::from_bytes(
+            text.as_bytes(),
+            mode,
+            eci,
+            min_version,
+            strict_version,
+            min_ecl,
+            strict_ecl,
+        )
+    }
+
+    /// Like `new_verbose`, but input can be any bytes when mode is Byte
+    pub fn from_bytes(
+        input: &[u8],
+        mode: Mode,
+        eci: Option<Eci>,
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        let mut segments = Vec::with_capacity(2);
+        if let Some(eci) = eci {
+            segments.push(Segment::Eci(eci));
+        }
+        segments.push(Segment::from_bytes(mode, input));
+
+        Self::from_segments(segments, min_version, strict_version, min_ecl, strict_ecl)
+    }
+
+    /// Like `new_verbose`, but text is split into segments of different modes to use the fewest bits
+    pub fn new_segmented(
+        text: &str,
+        eci: Option<Eci>,
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        Self::from_bytes_segmented(
+            text.as_bytes(),
+            eci,
+            min_version,
+            strict_version,
+            min_ecl,
+            strict_ecl,
+        )
+    }
+
+    /// Like `new_segmented`, but Kanji is only considered if input is valid UTF-8
+    pub fn from_bytes_segmented(
+        input: &[u8],
+        eci: Option<Eci>,
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        let header: Vec<Segment> = eci.into_iter().map(Segment::Eci).collect();
+        Self::segmented(
+            &header,
+            input,
+            min_version,
+            strict_version,
+            min_ecl,
+            strict_ecl,
+        )
+    }
+
+    /// Segments in `header` are placed before the optimally split input
+    pub(crate) fn segmented(
+        header: &[Segment],
+        input: &[u8],
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        let fnc1 = header
+            .iter()
+            .any(|segment| matches!(segment, Segment::Fnc1(_)));
+        let segments = |version| {
+            let mut segments = header.to_vec();
+            segments.append(&mut optimal_segments_bytes(input, version, fnc1));
+            segments
+        };
+
+        if strict_version {
+            return Self::from_segments(
+                segments(min_version),
+                min_version,
+                strict_version,
+                min_ecl,
+                strict_ecl,
+            );
+        }
+
+        // char count indicator lengths change at versions 10 and 27,
+        // so the best split depends on which range the final version is in
+        for (first, last) in [(1, 9), (10, 26), (27, 40)] {
+            if last < min_version.0 {
+                continue;
             }
-            Mode::Byte => {
-                bits += char_len * 8;
+            let version = Version(min_version.0.max(first));
+            let data = Self::from_segments(segments(version), version, false, min_ecl, strict_ecl);
+            if let Some(data) = data {
+                if data.version.0 <= last {
+                    return Some(data);
+                }
             }
         }
-        let mut data_codewords = (NUM_DATA_MODULES[min_version.0] / 8) as usize;
+        None
+    }
+
+    pub fn from_segments(
+        segments: Vec<Segment>,
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        let num_bits = |version| {
+            segments
+                .iter()
+                .map(|segment| segment.num_bits(version))
+                .sum::<usize>()
+        };
 
         let mut min_version = min_version.0;
-        let mut req_codewords = (bits + 7) / 8;
+        let mut bits = num_bits(Version(min_version));
+
+        let mut data_codewords = (NUM_DATA_MODULES[min_version] / 8) as usize;
+        let mut req_codewords = bits.div_ceil(8);
 
         while req_codewords
             > (data_codewords - NUM_EC_CODEWORDS[min_version][min_ecl as usize] as usize)
-            && min_version < 40
         {
-            if strict_version {
+            if strict_version || min_version == 40 {
                 return None;
             }
 
@@ -61,23 +182,10 @@ impl Data {
 
             data_codewords = (NUM_DATA_MODULES[min_version] / 8) as usize;
             // char count indicator length increase
-            match mode {
-                Mode::Byte => {
-                    if min_version == 10 {
-                        bits += 8;
-                    }
-                }
-                _ => {
-                    if min_version == 10 || min_version == 27 {
-                        bits += 2;
-                    }
-                }
+            if min_version == 10 || min_version == 27 {
+                bits = num_bits(Version(min_version));
             }
-            req_codewords = (bits + 7) / 8;
-        }
-
-        if min_version > 40 {
-            return None;
+            req_codewords = bits.div_ceil(8);
         }
 
         let mut max_ecl = min_ecl;
@@ -95,21 +203,296 @@ impl Data {
 
         let mut data = Data {
             bits: BitVec::with_capacity(data_codewords * 8),
-            mode,
+            symbol: Symbol::Qr,
+            mode: common_mode(&segments),
             version: Version(min_version),
             ecl: max_ecl,
+            segments: Vec::new(),
         };
 
-        match mode {
-            Mode::Numeric => encode_numeric(&mut data, text),
-            Mode::Alphanumeric => encode_alphanumeric(&mut data, text),
-            Mode::Byte => encode_byte(&mut data, text),
+        for segment in segments.iter() {
+            segment.encode(&mut data);
         }
+        data.segments = segments;
+
         Some(data)
     }
+
+    /// Like `segmented`, but for Micro QR, see `from_segments_micro`
+    pub fn segmented_micro(
+        input: &[u8],
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        // char count indicator lengths change every version
+        let last = if strict_version { min_version.0 } else { 4 };
+        for version in min_version.0..=last {
+            let Some(segments) = optimal_micro_segments(input, Version(version)) else {
+                continue;
+            };
+            let data =
+                Self::from_segments_micro(segments, Version(version), true, min_ecl, strict_ecl);
+            if data.is_some() {
+                return data;
+            }
+        }
+        None
+    }
+
+    /// Micro QR version is 1 - 4 for M1 - M4.
+    ///
+    /// M1 only detects errors, which is ECL::Low here, and High is never supported.
+    /// None if it doesn't fit or a segment can't be encoded, e.g. ECI, FNC1, Structured Append.
+    pub fn from_segments_micro(
+        segments: Vec<Segment>,
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        let fits = |version: usize, ecl: usize, bits: usize| {
+            let capacity = MICRO_DATA_BITS[version][ecl] as usize;
+            capacity != 0 && bits <= capacity
+        };
+
+        let mut version = min_version.0;
+        let bits = loop {
+            if version > 4 {
+                return None;
+            }
+            let bits = segments
+                .iter()
+                .map(|segment| segment.num_micro_bits(Version(version)))
+                .sum::<Option<usize>>();
+            if let Some(bits) = bits {
+                if fits(version, min_ecl as usize, bits) {
+                    break bits;
+                }
+            }
+            if strict_version {
+                return None;
+            }
+            version += 1;
+        };
+
+        let mut max_ecl = min_ecl;
+
+        if !strict_ecl {
+            let ecls = [ECL::Low, ECL::Medium, ECL::Quartile, ECL::High];
+            for new_ecl in (min_ecl as usize + 1..ecls.len()).rev() {
+                if fits(version, new_ecl, bits) {
+                    max_ecl = ecls[new_ecl];
+                    break;
+                }
+            }
+        }
+
+        let mut data = Data {
+            bits: BitVec::with_capacity(MICRO_DATA_BITS[version][max_ecl as usize] as usize),
+            symbol: Symbol::Micro,
+            mode: common_mode(&segments),
+            version: Version(version),
+            ecl: max_ecl,
+            segments: Vec::new(),
+        };
+
+        for segment in segments.iter() {
+            segment.encode(&mut data);
+        }
+        data.segments = segments;
+
+        Some(data)
+    }
+
+    /// Like `segmented`, but for rMQR, see `from_segments_rmqr`
+    pub fn segmented_rmqr(
+        input: &[u8],
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        // char count indicator lengths change every version
+        let last = if strict_version { min_version.0 } else { 32 };
+        for version in min_version.0..=last {
+            let segments = optimal_rmqr_segments(input, Version(version));
+            let data =
+                Self::from_segments_rmqr(segments, Version(version), true, min_ecl, strict_ecl);
+            if data.is_some() {
+                return data;
+            }
+        }
+        None
+    }
+
+    /// rMQR version is 1 - 32 for R7x43 - R17x139, see `RMQR_SIZES`.
+    ///
+    /// Versions are tried in order, so shorter symbols are preferred over narrower ones.
+    /// Only Medium and High exist, so Low becomes Medium and Quartile becomes High.
+    /// None if it doesn't fit or a segment can't be encoded, e.g. ECI, FNC1, Structured Append.
+    pub fn from_segments_rmqr(
+        segments: Vec<Segment>,
+        min_version: Version,
+        strict_version: bool,
+        min_ecl: ECL,
+        strict_ecl: bool,
+    ) -> Option<Self> {
+        let capacity = |version: usize, ecl: ECL| {
+            let codewords = RMQR_DATA_MODULES[version] as usize / 8;
+            (codewords - RMQR_EC_CODEWORDS[version][ecl as usize] as usize) * 8
+        };
+        let min_ecl = match min_ecl {
+            ECL::Low | ECL::Medium => ECL::Medium,
+            ECL::Quartile | ECL::High => ECL::High,
+        };
+
+        let mut version = min_version.0;
+        let bits = loop {
+            if version > 32 {
+                return None;
+            }
+            let bits = segments
+                .iter()
+                .map(|segment| segment.num_rmqr_bits(Version(version)))
+                .sum::<Option<usize>>()?;
+            if bits <= capacity(version, min_ecl) {
+                break bits;
+            }
+            if strict_version {
+                return None;
+            }
+            version += 1;
+        };
+
+        let ecl = if !strict_ecl && bits <= capacity(version, ECL::High) {
+            ECL::High
+        } else {
+            min_ecl
+        };
+
+        let mut data = Data {
+            bits: BitVec::with_capacity(capacity(version, ecl)),
+            symbol: Symbol::Rmqr,
+            mode: common_mode(&segments),
+            version: Version(version),
+            ecl,
+            segments: Vec::new(),
+        };
+
+        for segment in segments.iter() {
+            segment.encode(&mut data);
+        }
+        data.segments = segments;
+
+        Some(data)
+    }
+}
+
+/// Mode of all segments, or Byte if segments use different modes
+fn common_mode(segments: &[Segment]) -> Mode {
+    let mut mode = None;
+    for segment_mode in segments.iter().filter_map(|segment| segment.mode()) {
+        mode = match mode {
+            Some(mode) if mode != segment_mode => Some(Mode::Byte),
+            _ => Some(segment_mode),
+        };
+    }
+    mode.unwrap_or(Mode::Numeric)
+}
+
+/// Builds `Data` from segments in the order they are pushed
+#[derive(Debug)]
+pub struct DataBuilder {
+    segments: Vec<Segment>,
+    fnc1: bool,
+    min_version: Version,
+    strict_version: bool,
+    min_ecl: ECL,
+    strict_ecl: bool,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+impl DataBuilder {
+    pub fn new() -> Self {
+        DataBuilder {
+            segments: Vec::new(),
+            fnc1: false,
+            min_version: Version(1),
+            strict_version: false,
+            min_ecl: ECL::Low,
+            strict_ecl: false,
+        }
+    }
+    pub fn segment(mut self, segment: Segment) -> Self {
+        self.segments.push(segment);
+        self
+    }
+    pub fn eci(self, eci: Eci) -> Self {
+        self.segment(Segment::Eci(eci))
+    }
+    /// GS separators (0x1D) in later alphanumeric segments will be encoded as `%`
+    pub fn fnc1(mut self, fnc1: Fnc1) -> Self {
+        self.fnc1 = true;
+        self.segment(Segment::Fnc1(fnc1))
+    }
+    pub fn numeric(self, text: &str) -> Self {
+        self.segment(Segment::Numeric(text.into()))
+    }
+    pub fn alphanumeric(self, text: &str) -> Self {
+        if self.fnc1 {
+            let text = escape_fnc1(text.as_bytes());
+            return self.segment(Segment::Alphanumeric(text));
+        }
+        self.segment(Segment::Alphanumeric(text.into()))
+    }
+    pub fn byte(self, bytes: &[u8]) -> Self {
+        self.segment(Segment::Byte(bytes.into()))
+    }
+    pub fn kanji(self, text: &str) -> Self {
+        self.segment(Segment::Kanji(text.into()))
+    }
+    pub fn min_version(mut self, version: Version) -> Self {
+        self.min_version = version;
+        self
+    }
+    pub fn min_ecl(mut self, ecl: ECL) -> Self {
+        self.min_ecl = ecl;
+        self
+    }
+    pub fn strict_version(mut self, strict: bool) -> Self {
+        self.strict_version = strict;
+        self
+    }
+    pub fn strict_ecl(mut self, strict: bool) -> Self {
+        self.strict_ecl = strict;
+        self
+    }
+    pub fn build(self) -> Result<Data, QrError> {
+        if !self.segments.iter().all(|segment| segment.is_valid()) {
+            return Err(QrError::InvalidEncoding);
+        }
+
+        match Data::from_segments(
+            self.segments,
+            self.min_version,
+            self.strict_version,
+            self.min_ecl,
+            self.strict_ecl,
+        ) {
+            Some(data) => Ok(data),
+            None => Err(QrError::ExceedsMaxCapacity),
+        }
+    }
+}
+
+impl Default for DataBuilder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
 pub struct BitVec {
     value: Vec<u8>,
     len: usize,
@@ -150,6 +533,9 @@ impl BitVec {
         self.len
     }
     pub fn push_n(&mut self, input: usize, n: usize) {
+        if n == 0 {
+            return;
+        }
         let gap = (8 - (self.len % 8)) % 8;
         self.len += n;
 
@@ -196,3 +582,50 @@ impl AsMut<[u8]> for BitVec {
         &mut self.value
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{qart::Qart, qr_code::QrCode};
+
+    #[test]
+    fn builder_works() {
+        let data = Data::builder()
+            .byte(&[0xFF, 0x00])
+            .numeric("123")
+            .build()
+            .unwrap();
+
+        let mut expected = BitVec::new();
+        expected.push_n(0b0100, 4);
+        expected.push_n(2, 8);
+        expected.push_n(0xFF00, 16);
+        expected.push_n(0b0001, 4);
+        expected.push_n(3, 10);
+        expected.push_n(123, 10);
+
+        assert_eq!(data.bits, expected);
+        assert_eq!(data.mode, Mode::Byte);
+        assert_eq!(data.version, Version(1));
+        assert_eq!(data.ecl, ECL::High);
+
+        let qr_code = QrCode::new(data, None);
+        assert_eq!(qr_code.segments.len(), 2);
+
+        let data = Data::builder().eci(Eci::UTF8).byte("é".as_bytes());
+        Qart::new(data.build().unwrap(), crate::qr_code::Mask::M0);
+    }
+
+    #[test]
+    fn builder_errors() {
+        let data = Data::builder().numeric("12a").build();
+        assert_eq!(data.unwrap_err(), QrError::InvalidEncoding);
+
+        let data = Data::builder()
+            .byte(&[0; 100])
+            .min_version(Version(2))
+            .strict_version(true)
+            .build();
+        assert_eq!(data.unwrap_err(), QrError::ExceedsMaxCapacity);
+    }
+}
@@ -0,0 +1,443 @@
+use crate::{
+    constants::{FORMAT_INFO, NUM_BLOCKS, NUM_DATA_MODULES, NUM_EC_CODEWORDS, VERSION_INFO},
+    encoding::num_cci_bits,
+    error_correction::correct_with_erasures,
+    matrix::{format_positions, version_positions, Matrix, Module},
+    qr_code::{mask_fn, Eci, Fnc1, Mask, Mode, Version, ECL},
+    segment::Segment,
+    shift_jis::from_shift_jis,
+    template::Template,
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DecodeError {
+    /// Not a square matrix with a valid QR width
+    InvalidSize,
+    /// More than 3 bit errors in both copies
+    InvalidFormat,
+    /// More than 3 bit errors in both copies, or doesn't match width
+    InvalidVersion,
+    /// Too many errors in an error correction block
+    Uncorrectable,
+    /// Corrected data isn't a valid sequence of segments
+    InvalidData,
+}
+
+#[derive(Debug)]
+pub struct Decoded {
+    pub version: Version,
+    pub ecl: ECL,
+    pub mask: Mask,
+    pub segments: Vec<Segment>,
+    /// Number of corrected codewords in each error correction block
+    pub errors: Vec<usize>,
+}
+
+impl Decoded {
+    /// Contents of all data segments. Kanji becomes UTF-8, and FNC1 `%` becomes GS (0x1D).
+    pub fn bytes(&self) -> Vec<u8> {
+        let fnc1 = self
+            .segments
+            .iter()
+            .any(|segment| matches!(segment, Segment::Fnc1(_)));
+
+        let mut bytes = Vec::new();
+        for segment in self.segments.iter() {
+            match segment {
+                Segment::Numeric(text) | Segment::Kanji(text) => {
+                    bytes.extend_from_slice(text.as_bytes())
+                }
+                Segment::Alphanumeric(text) if fnc1 => {
+                    let mut chars = text.bytes().peekable();
+                    while let Some(c) = chars.next() {
+                        if c != b'%' {
+                            bytes.push(c);
+                        } else if chars.next_if_eq(&b'%').is_some() {
+                            bytes.push(b'%');
+                        } else {
+                            bytes.push(0x1D);
+                        }
+                    }
+                }
+                Segment::Alphanumeric(text) => bytes.extend_from_slice(text.as_bytes()),
+                Segment::Byte(data) => bytes.extend_from_slice(data),
+                _ => (),
+            }
+        }
+        bytes
+    }
+
+    /// Same as `bytes`, but invalid UTF-8 is replaced
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.bytes()).into()
+    }
+}
+
+/// Decodes a QR code matrix, only the ON bit of each module is read.
+pub fn decode(matrix: &Matrix<Module>) -> Result<Decoded, DecodeError> {
+    decode_with_erasures(matrix, &[])
+}
+
+/// Same as `decode`, but `erased` modules are known to be unreliable, e.g. covered by a logo.
+///
+/// Codewords containing an erased module are corrected as erasures, which cost half as much
+/// error correction as unknown errors.
+pub fn decode_with_erasures(
+    matrix: &Matrix<Module>,
+    erased: &[(usize, usize)],
+) -> Result<Decoded, DecodeError> {
+    let width = matrix.width;
+    if width != matrix.height || !(21..=177).contains(&width) || width % 4 != 1 {
+        return Err(DecodeError::InvalidSize);
+    }
+    let version = Version((width - 17) / 4);
+
+    let (ecl, mask) = read_format(matrix)?;
+    if version.0 >= 7 && read_version(matrix)? != version {
+        return Err(DecodeError::InvalidVersion);
+    }
+
+    let (codewords, erased) = read_codewords(matrix, version, mask, erased);
+
+    let num_ec_codewords = NUM_EC_CODEWORDS[version.0][ecl as usize] as usize;
+    let num_data_codewords = codewords.len() - num_ec_codewords;
+    let blocks = NUM_BLOCKS[version.0][ecl as usize] as usize;
+
+    let group_2_blocks = codewords.len() % blocks;
+    let group_1_blocks = blocks - group_2_blocks;
+
+    let data_per_g1_block = num_data_codewords / blocks;
+    let ecc_per_block = num_ec_codewords / blocks;
+
+    // inverse of interleaving in ecc_and_sequence, group 2 blocks have 1 more data codeword
+    let order = (0..=data_per_g1_block)
+        .flat_map(|col| {
+            (0..blocks).filter(move |&i| col < data_per_g1_block || i >= group_1_blocks)
+        })
+        .chain((0..ecc_per_block).flat_map(|_| 0..blocks));
+
+    let mut block_codewords =
+        vec![Vec::with_capacity(data_per_g1_block + 1 + ecc_per_block); blocks];
+    let mut block_erasures = vec![Vec::new(); blocks];
+    for ((block, codeword), erased) in order.zip(codewords).zip(erased) {
+        if erased {
+            block_erasures[block].push(block_codewords[block].len());
+        }
+        block_codewords[block].push(codeword);
+    }
+
+    let mut data = Vec::with_capacity(num_data_codewords);
+    let mut errors = Vec::with_capacity(blocks);
+    for (mut block, erasures) in block_codewords.into_iter().zip(block_erasures) {
+        let corrected = correct_with_erasures(&mut block, ecc_per_block, &erasures)
+            .ok_or(DecodeError::Uncorrectable)?;
+        errors.push(corrected);
+        data.extend_from_slice(&block[..block.len() - ecc_per_block]);
+    }
+
+    Ok(Decoded {
+        version,
+        ecl,
+        mask,
+        segments: read_segments(&data, version)?,
+        errors,
+    })
+}
+
+/// Format info with the fewest bit errors out of both copies
+fn read_format(matrix: &Matrix<Module>) -> Result<(ECL, Mask), DecodeError> {
+    let mut copies = [0; 2];
+    for i in 0..15 {
+        for (copy, (x, y)) in format_positions(matrix.width, i).into_iter().enumerate() {
+            copies[copy] |= (matrix.get(x, y).has(Module::ON) as u32) << i;
+        }
+    }
+
+    let ecls = [ECL::Low, ECL::Medium, ECL::Quartile, ECL::High];
+    let masks = [
+        Mask::M0,
+        Mask::M1,
+        Mask::M2,
+        Mask::M3,
+        Mask::M4,
+        Mask::M5,
+        Mask::M6,
+        Mask::M7,
+    ];
+
+    let mut best = (u32::MAX, ECL::Low, Mask::M0);
+    for ecl in ecls {
+        for mask in masks {
+            for copy in copies {
+                let distance = (copy ^ FORMAT_INFO[ecl as usize][mask as usize]).count_ones();
+                if distance < best.0 {
+                    best = (distance, ecl, mask);
+                }
+            }
+        }
+    }
+
+    if best.0 > 3 {
+        return Err(DecodeError::InvalidFormat);
+    }
+    Ok((best.1, best.2))
+}
+
+/// Version info with the fewest bit errors out of both copies
+fn read_version(matrix: &Matrix<Module>) -> Result<Version, DecodeError> {
+    let mut copies = [0; 2];
+    for i in 0..18 {
+        for (copy, (x, y)) in version_positions(matrix.width, i).into_iter().enumerate() {
+            copies[copy] |= (matrix.get(x, y).has(Module::ON) as usize) << i;
+        }
+    }
+
+    let mut best = (u32::MAX, 0);
+    for (version, info) in VERSION_INFO.iter().enumerate().skip(7) {
+        for copy in copies {
+            let distance = (copy ^ info).count_ones();
+            if distance < best.0 {
+                best = (distance, version);
+            }
+        }
+    }
+
+    if best.0 > 3 {
+        return Err(DecodeError::InvalidVersion);
+    }
+    Ok(Version(best.1))
+}
+
+/// Unmasked codewords in placement order, and whether each contains an erased module.
+/// Remainder bits are skipped.
+fn read_codewords(
+    matrix: &Matrix<Module>,
+    version: Version,
+    mask: Mask,
+    erased: &[(usize, usize)],
+) -> (Vec<u8>, Vec<bool>) {
+    let template = Template::get(version);
+    let modules = NUM_DATA_MODULES[version.0] as usize;
+    let width = template.matrix.width;
+
+    let mut erased_modules = vec![false; width * width];
+    for &(x, y) in erased {
+        if x < width && y < width {
+            erased_modules[y * width + x] = true;
+        }
+    }
+
+    let mask_bit = mask_fn(mask);
+    let mut codewords = vec![0; modules / 8];
+    let mut erased_codewords = vec![false; modules / 8];
+    for (i, &(x, y)) in template.data.iter().take(codewords.len() * 8).enumerate() {
+        let (x, y) = (x as usize, y as usize);
+        let on = matrix.get(x, y).has(Module::ON) ^ mask_bit(x as u16, y as u16);
+        codewords[i / 8] |= (on as u8) << (7 - (i % 8));
+        erased_codewords[i / 8] |= erased_modules[y * width + x];
+    }
+    (codewords, erased_codewords)
+}
+
+struct BitReader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl BitReader<'_> {
+    fn remaining(&self) -> usize {
+        self.bytes.len() * 8 - self.i
+    }
+
+    fn read(&mut self, n: usize) -> Result<usize, DecodeError> {
+        if n > self.remaining() {
+            return Err(DecodeError::InvalidData);
+        }
+        let mut value = 0;
+        for _ in 0..n {
+            let bit = (self.bytes[self.i / 8] >> (7 - (self.i % 8))) & 1;
+            value = (value << 1) | bit as usize;
+            self.i += 1;
+        }
+        Ok(value)
+    }
+}
+
+const ALPHANUMERIC: &[u8; 45] = b"0123456789ABCDEFGHIJKLMNOPQRSTUVWXYZ $%*+-./:";
+
+fn read_segments(data: &[u8], version: Version) -> Result<Vec<Segment>, DecodeError> {
+    let mut reader = BitReader { bytes: data, i: 0 };
+    let mut segments = Vec::new();
+
+    // terminator can be cut short if data is full
+    while reader.remaining() >= 4 {
+        let segment = match reader.read(4)? {
+            0b0000 => break,
+            0b0111 => {
+                let first = reader.read(8)?;
+                let eci = match first {
+                    x if x & 0b1000_0000 == 0 => x,
+                    x if x & 0b1100_0000 == 0b1000_0000 => {
+                        ((x & 0b0011_1111) << 8) | reader.read(8)?
+                    }
+                    x if x & 0b1110_0000 == 0b1100_0000 => {
+                        ((x & 0b0001_1111) << 16) | reader.read(16)?
+                    }
+                    _ => return Err(DecodeError::InvalidData),
+                };
+                match Eci::try_new(eci as u32) {
+                    Ok(eci) => Segment::Eci(eci),
+                    Err(_) => return Err(DecodeError::InvalidData),
+                }
+            }
+            0b0011 => Segment::StructuredAppend {
+                index: reader.read(4)? as u8,
+                total: reader.read(4)? as u8 + 1,
+                parity: reader.read(8)? as u8,
+            },
+            0b0101 => Segment::Fnc1(Fnc1::First),
+            0b1001 => Segment::Fnc1(Fnc1::Second(reader.read(8)? as u8)),
+            0b0001 => {
+                let len = reader.read(num_cci_bits(version, Mode::Numeric))?;
+                let mut text = String::with_capacity(len);
+                let mut remaining = len;
+                while remaining > 0 {
+                    let digits = remaining.min(3);
+                    let group = reader.read([0, 4, 7, 10][digits])?;
+                    if group >= 10usize.pow(digits as u32) {
+                        return Err(DecodeError::InvalidData);
+                    }
+                    text.push_str(&format!("{:0width$}", group, width = digits));
+                    remaining -= digits;
+                }
+                Segment::Numeric(text)
+            }
+            0b0010 => {
+                let len = reader.read(num_cci_bits(version, Mode::Alphanumeric))?;
+                let mut text = String::with_capacity(len);
+                for _ in 0..len / 2 {
+                    let group = reader.read(11)?;
+                    if group >= 45 * 45 {
+                        return Err(DecodeError::InvalidData);
+                    }
+                    text.push(ALPHANUMERIC[group / 45] as char);
+                    text.push(ALPHANUMERIC[group % 45] as char);
+                }
+                if len % 2 == 1 {
+                    let c = *ALPHANUMERIC
+                        .get(reader.read(6)?)
+                        .ok_or(DecodeError::InvalidData)?;
+                    text.push(c as char);
+                }
+                Segment::Alphanumeric(text)
+            }
+            0b0100 => {
+                let len = reader.read(num_cci_bits(version, Mode::Byte))?;
+                let mut bytes = Vec::with_capacity(len);
+                for _ in 0..len {
+                    bytes.push(reader.read(8)? as u8);
+                }
+                Segment::Byte(bytes)
+            }
+            0b1000 => {
+                let len = reader.read(num_cci_bits(version, Mode::Kanji))?;
+                let mut text = String::with_capacity(len * 3);
+                for _ in 0..len {
+                    let group = reader.read(13)?;
+                    let sjis = ((group / 0xC0) << 8) | (group % 0xC0);
+                    let sjis = if sjis + 0x8140 <= 0x9FFC {
+                        sjis + 0x8140
+                    } else {
+                        sjis + 0xC140
+                    };
+                    let c = from_shift_jis(sjis as u16).ok_or(DecodeError::InvalidData)?;
+                    text.push(c);
+                }
+                Segment::Kanji(text)
+            }
+            _ => return Err(DecodeError::InvalidData),
+        };
+        segments.push(segment);
+    }
+
+    Ok(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate, generate_bytes, QrOptions};
+
+    #[test]
+    fn decode_works() {
+        let input = "HTTPS://EXAMPLE.COM/ORDER/123456789012?q=abc点茗";
+        let qr_code = generate(input, &QrOptions::new().min_ecl(ECL::Medium)).unwrap();
+        let decoded = decode(&qr_code.matrix).unwrap();
+        assert_eq!(decoded.version, qr_code.version);
+        assert_eq!(decoded.ecl, qr_code.ecl);
+        assert_eq!(decoded.mask, qr_code.mask);
+        assert_eq!(decoded.segments, qr_code.segments);
+        assert_eq!(decoded.text(), input);
+        assert!(decoded.errors.iter().all(|&errors| errors == 0));
+
+        let input = [0xDE, 0xAD, 0xBE, 0xEF].repeat(100);
+        let qr_code = generate_bytes(&input, &QrOptions::new().min_ecl(ECL::High)).unwrap();
+        let decoded = decode(&qr_code.matrix).unwrap();
+        assert!(decoded.version.0 >= 7);
+        assert_eq!(decoded.bytes(), input);
+    }
+
+    #[test]
+    fn decode_corrects_errors() {
+        let qr_code = generate(
+            "https://github.com/zhengkyl/fuqr",
+            &QrOptions::new()
+                .min_version(Version(5))
+                .min_ecl(ECL::Quartile)
+                .strict_ecl(true),
+        )
+        .unwrap();
+
+        let mut matrix = Matrix::with_size(qr_code.matrix.width, qr_code.matrix.height, Module(0));
+        matrix.value = qr_code.matrix.value.clone();
+        // flip a format bit and a column of data modules
+        matrix.value[8] ^= Module::ON;
+        for y in 9..matrix.height - 9 {
+            *matrix.get_mut(matrix.width - 3, y) ^= Module::ON;
+        }
+
+        let decoded = decode(&matrix).unwrap();
+        assert_eq!(decoded.text(), "https://github.com/zhengkyl/fuqr");
+        assert!(decoded.errors.iter().sum::<usize>() > 0);
+        assert!(decoded.errors.iter().all(|&errors| errors <= 9));
+    }
+
+    #[test]
+    fn decode_with_erasures_works() {
+        let input = "https://github.com/zhengkyl/fuqr";
+        let qr_code = generate(
+            input,
+            &QrOptions::new()
+                .min_version(Version(3))
+                .min_ecl(ECL::Low)
+                .strict_ecl(true),
+        )
+        .unwrap();
+
+        // cover a square in the middle, too many errors without erasures
+        let mut matrix = Matrix::with_size(qr_code.matrix.width, qr_code.matrix.height, Module(0));
+        matrix.value = qr_code.matrix.value.clone();
+        let mut erased = Vec::new();
+        for y in 11..18 {
+            for x in 11..18 {
+                matrix.set(x, y, Module(0));
+                erased.push((x, y));
+            }
+        }
+
+        assert_eq!(decode(&matrix).unwrap_err(), DecodeError::Uncorrectable);
+
+        let decoded = decode_with_erasures(&matrix, &erased).unwrap();
+        assert_eq!(decoded.text(), input);
+    }
+}
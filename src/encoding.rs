@@ -1,11 +1,18 @@
 use crate::{
+    constants::RMQR_CCI_BITS,
     data::Data,
-    qr_code::{Mode, Version},
+    qr_code::{Eci, Fnc1, Mode, Symbol, Version},
+    shift_jis::to_shift_jis,
 };
 
 pub fn encoding_mode(input: &str) -> Mode {
+    encoding_mode_bytes(input.as_bytes())
+}
+
+/// Same as `encoding_mode`, but Kanji is only possible if input is valid UTF-8
+pub fn encoding_mode_bytes(input: &[u8]) -> Mode {
     let mut mode = Mode::Numeric;
-    for b in input.bytes() {
+    for &b in input {
         if b >= b'0' && b <= b'9' {
             continue;
         }
@@ -16,14 +23,47 @@ pub fn encoding_mode(input: &str) -> Mode {
             break;
         }
     }
+    if mode == Mode::Byte {
+        if let Ok(text) = std::str::from_utf8(input) {
+            if text.chars().all(|c| to_shift_jis(c).is_some()) {
+                mode = Mode::Kanji;
+            }
+        }
+    }
     mode
 }
 
+/// Mode indicator and char count indicator
+fn encode_header(qrdata: &mut Data, mode: Mode, len: usize) {
+    match qrdata.symbol {
+        Symbol::Qr => {
+            let indicator = match mode {
+                Mode::Numeric => 0b0001,
+                Mode::Alphanumeric => 0b0010,
+                Mode::Byte => 0b0100,
+                Mode::Kanji => 0b1000,
+            };
+            qrdata.bits.push_n(indicator, 4);
+            qrdata.bits.push_n(len, num_cci_bits(qrdata.version, mode));
+        }
+        Symbol::Micro => {
+            qrdata.bits.push_n(mode as usize, qrdata.version.0 - 1);
+            qrdata
+                .bits
+                .push_n(len, num_micro_cci_bits(qrdata.version, mode));
+        }
+        Symbol::Rmqr => {
+            qrdata.bits.push_n(mode as usize + 1, 3);
+            qrdata
+                .bits
+                .push_n(len, num_rmqr_cci_bits(qrdata.version, mode));
+        }
+    }
+}
+
 // input fits in u8 b/c numeric
 pub fn encode_numeric(data: &mut Data, input: &str) {
-    data.bits.push_n(0b0001, 4);
-    data.bits
-        .push_n(input.len(), num_cci_bits(data.version, Mode::Numeric));
+    encode_header(data, Mode::Numeric, input.len());
 
     let input = input.as_bytes();
     for i in 0..(input.len() / 3) {
@@ -47,11 +87,7 @@ pub fn encode_numeric(data: &mut Data, input: &str) {
 }
 
 pub fn encode_alphanumeric(qrdata: &mut Data, input: &str) {
-    qrdata.bits.push_n(0b0010, 4);
-    qrdata.bits.push_n(
-        input.len(),
-        num_cci_bits(qrdata.version, Mode::Alphanumeric),
-    );
+    encode_header(qrdata, Mode::Alphanumeric, input.len());
 
     let input = input.as_bytes();
 
@@ -68,16 +104,72 @@ pub fn encode_alphanumeric(qrdata: &mut Data, input: &str) {
     }
 }
 
-pub fn encode_byte(qrdata: &mut Data, input: &str) {
-    qrdata.bits.push_n(0b0100, 4);
-    qrdata
-        .bits
-        .push_n(input.len(), num_cci_bits(qrdata.version, Mode::Byte));
-    for c in input.as_bytes() {
+pub fn encode_byte(qrdata: &mut Data, input: &[u8]) {
+    encode_header(qrdata, Mode::Byte, input.len());
+    for c in input {
         qrdata.bits.push_n((*c).into(), 8);
     }
 }
 
+pub fn encode_kanji(qrdata: &mut Data, input: &str) {
+    encode_header(qrdata, Mode::Kanji, input.chars().count());
+
+    for c in input.chars() {
+        // invalid characters become a full width space
+        let sjis = to_shift_jis(c).unwrap_or(0x8140) as usize;
+        let sjis = if sjis <= 0x9FFC {
+            sjis - 0x8140
+        } else {
+            sjis - 0xC140
+        };
+        let group = (sjis >> 8) * 0xC0 + (sjis & 0xFF);
+        qrdata.bits.push_n(group, 13);
+    }
+}
+
+pub fn encode_eci(qrdata: &mut Data, eci: Eci) {
+    qrdata.bits.push_n(0b0111, 4);
+    match eci.value() {
+        x if x < 128 => qrdata.bits.push_n(x as usize, 8),
+        x if x < 16384 => qrdata.bits.push_n((0b10 << 14) | x as usize, 16),
+        x => qrdata.bits.push_n((0b110 << 21) | x as usize, 24),
+    }
+}
+
+/// Header for symbol `index` out of `total` symbols
+pub fn encode_structured_append(qrdata: &mut Data, index: u8, total: u8, parity: u8) {
+    qrdata.bits.push_n(0b0011, 4);
+    qrdata.bits.push_n(index.into(), 4);
+    qrdata.bits.push_n((total - 1).into(), 4);
+    qrdata.bits.push_n(parity.into(), 8);
+}
+
+pub fn encode_fnc1(qrdata: &mut Data, fnc1: Fnc1) {
+    match fnc1 {
+        Fnc1::First => qrdata.bits.push_n(0b0101, 4),
+        Fnc1::Second(application) => {
+            qrdata.bits.push_n(0b1001, 4);
+            qrdata.bits.push_n(application.into(), 8);
+        }
+    }
+}
+
+/// GS separators become `%`, so `%` must be escaped as `%%`
+pub fn escape_fnc1(input: &[u8]) -> String {
+    String::from_utf8_lossy(input)
+        .replace('%', "%%")
+        .replace('\x1D', "%")
+}
+
+/// Length of ECI header, including mode indicator
+pub fn num_eci_bits(eci: Eci) -> usize {
+    4 + match eci.value() {
+        x if x < 128 => 8,
+        x if x < 16384 => 16,
+        _ => 24,
+    }
+}
+
 pub fn num_cci_bits(version: Version, mode: Mode) -> usize {
     if mode == Mode::Byte {
         return if version.0 < 10 { 8 } else { 16 };
@@ -87,6 +179,7 @@ pub fn num_cci_bits(version: Version, mode: Mode) -> usize {
     let mut base = match mode {
         Mode::Numeric => 10,
         Mode::Alphanumeric => 9,
+        Mode::Kanji => 8,
         _ => unreachable!("Unknown mode"),
     };
     if version.0 > 9 {
@@ -98,7 +191,21 @@ pub fn num_cci_bits(version: Version, mode: Mode) -> usize {
     base
 }
 
-fn byte_to_b45(c: u8) -> u8 {
+/// Micro QR version is 1 - 4 for M1 - M4, mode indicator is version - 1 bits
+pub fn num_micro_cci_bits(version: Version, mode: Mode) -> usize {
+    match mode {
+        Mode::Numeric => version.0 + 2,
+        Mode::Alphanumeric | Mode::Byte => version.0 + 1,
+        Mode::Kanji => version.0,
+    }
+}
+
+/// rMQR mode indicator is always 3 bits
+pub fn num_rmqr_cci_bits(version: Version, mode: Mode) -> usize {
+    RMQR_CCI_BITS[version.0][mode as usize] as usize
+}
+
+pub(crate) fn byte_to_b45(c: u8) -> u8 {
     match c {
         x if x >= b'A' && x <= b'Z' => x - b'A' + 10,
         b':' => 44,
@@ -119,32 +226,18 @@ fn byte_to_b45(c: u8) -> u8 {
 
 #[cfg(test)]
 mod tests {
-    use crate::{data::BitVec, qr_code::ECL};
+    use crate::{data::BitVec, qr_code::ECL, QrError};
 
     use super::*;
     fn get_data_bits(bits: &str) -> BitVec {
         let mut v = BitVec::new();
 
-        let mut i = 0;
-        let mut num = 0;
         for c in bits.chars() {
             match c {
-                '1' => {
-                    num += 1 << (7 - i);
-                    i += 1;
-                }
-                '0' => i += 1,
+                '1' => v.push_n(1, 1),
+                '0' => v.push_n(0, 1),
                 _ => continue,
             }
-            if i == 8 {
-                v.push_n(num, 1);
-                num = 0;
-                i = 0;
-            }
-        }
-
-        if i > 0 {
-            v.push_n(num, 1);
         }
 
         v
@@ -187,4 +280,75 @@ mod tests {
 
         assert_eq!(data.bits, get_data_bits("0100 00000001 00110000"));
     }
+
+    #[test]
+    fn encode_kanji_works() {
+        let data = Data::new("点茗", Mode::Kanji, Version(1), ECL::Low).unwrap();
+        assert_eq!(
+            data.bits,
+            get_data_bits("1000 00000010 0110110011111 1101010101010")
+        );
+    }
+
+    #[test]
+    fn encode_eci_works() {
+        let data = Data::new_verbose(
+            "",
+            Mode::Byte,
+            Some(Eci::UTF8),
+            Version(1),
+            false,
+            ECL::Low,
+            false,
+        )
+        .unwrap();
+        assert_eq!(data.bits, get_data_bits("0111 00011010 0100 00000000"));
+
+        let mut data = Data::new("", Mode::Byte, Version(1), ECL::Low).unwrap();
+        data.bits = BitVec::new();
+        encode_eci(&mut data, Eci::new(1000));
+        assert_eq!(data.bits, get_data_bits("0111 10 00001111101000"));
+        assert_eq!(data.bits.len(), num_eci_bits(Eci::new(1000)));
+
+        data.bits = BitVec::new();
+        encode_eci(&mut data, Eci::new(999999));
+        assert_eq!(data.bits, get_data_bits("0111 110 011110100001000111111"));
+        assert_eq!(data.bits.len(), num_eci_bits(Eci::new(999999)));
+
+        assert_eq!(Eci::try_new(999999), Ok(Eci::new(999999)));
+        assert_eq!(Eci::try_new(1_000_000), Err(QrError::InvalidEncoding));
+    }
+
+    #[test]
+    fn encode_fnc1_works() {
+        let data = Data::builder()
+            .fnc1(Fnc1::First)
+            .alphanumeric("10AB\x1D21%")
+            .build()
+            .unwrap();
+        assert_eq!(
+            data.bits,
+            get_data_bits(
+                "0101 0010 000001001 00000101101 00111001101 11010110000 00001010011 100110"
+            )
+        );
+
+        let data = Data::builder()
+            .fnc1(Fnc1::Second(b'a' + 100))
+            .build()
+            .unwrap();
+        assert_eq!(data.bits, get_data_bits("1001 11000101"));
+
+        assert_eq!(escape_fnc1(b"01\x1D%"), "01%%%");
+    }
+
+    #[test]
+    fn encoding_mode_works() {
+        assert_eq!(encoding_mode("0123"), Mode::Numeric);
+        assert_eq!(encoding_mode("HELLO WORLD"), Mode::Alphanumeric);
+        assert_eq!(encoding_mode("hello"), Mode::Byte);
+        assert_eq!(encoding_mode("点茗"), Mode::Kanji);
+        assert_eq!(encoding_mode("点茗a"), Mode::Byte);
+        assert_eq!(encoding_mode_bytes(&[0x93, 0x5F]), Mode::Byte);
+    }
 }
@@ -1,24 +1,54 @@
+use std::sync::OnceLock;
+
 use crate::{
-    constants::{GEN_POLYNOMIALS, NUM_BLOCKS, NUM_DATA_MODULES, NUM_EC_CODEWORDS},
+    constants::{
+        GEN_POLYNOMIALS, MICRO_DATA_BITS, MICRO_EC_CODEWORDS, NUM_BLOCKS, NUM_DATA_MODULES,
+        NUM_EC_CODEWORDS, RMQR_BLOCKS, RMQR_DATA_MODULES, RMQR_EC_CODEWORDS,
+    },
     data::Data,
-    math::{EXP_TABLE, LOG_TABLE},
+    gf256::{Gf, Poly},
 };
 
-pub fn ecc_and_sequence(mut data: Data) -> Vec<u8> {
-    let modules = NUM_DATA_MODULES[data.version.0] as usize;
+pub fn ecc_and_sequence(data: Data) -> Vec<u8> {
+    let version = data.version.0;
+    let ecl = data.ecl as usize;
+    sequence(
+        data,
+        NUM_DATA_MODULES[version] as usize,
+        NUM_EC_CODEWORDS[version][ecl] as usize,
+        NUM_BLOCKS[version][ecl] as usize,
+        4,
+    )
+}
+
+/// Same as QR, except the terminator is 3 bits
+pub fn rmqr_ecc_and_sequence(data: Data) -> Vec<u8> {
+    let version = data.version.0;
+    let ecl = data.ecl as usize;
+    sequence(
+        data,
+        RMQR_DATA_MODULES[version] as usize,
+        RMQR_EC_CODEWORDS[version][ecl] as usize,
+        RMQR_BLOCKS[version][ecl] as usize,
+        3,
+    )
+}
+
+fn sequence(
+    mut data: Data,
+    modules: usize,
+    num_ec_codewords: usize,
+    blocks: usize,
+    term_len: usize,
+) -> Vec<u8> {
     let codewords = modules / 8;
     let remainder_bits = modules % 8;
 
-    let num_ec_codewords = NUM_EC_CODEWORDS[data.version.0][data.ecl as usize] as usize;
     let num_data_codewords = codewords - num_ec_codewords;
 
     // terminator
     let remainder_data_bits = (num_data_codewords * 8) - (data.bits.len());
-    let term_len = if remainder_data_bits < 4 {
-        remainder_data_bits
-    } else {
-        4
-    };
+    let term_len = term_len.min(remainder_data_bits);
     data.bits.push_n(0, term_len);
 
     // byte align
@@ -33,8 +63,6 @@ pub fn ecc_and_sequence(mut data: Data) -> Vec<u8> {
         alternating_byte ^= 0b1111_1101;
     }
 
-    let blocks = NUM_BLOCKS[data.version.0][data.ecl as usize] as usize;
-
     let group_2_blocks = codewords % blocks;
     let group_1_blocks = blocks - group_2_blocks;
 
@@ -61,33 +89,112 @@ pub fn ecc_and_sequence(mut data: Data) -> Vec<u8> {
             byte_vec[i + (group_1_blocks * data_per_g1_block)];
     }
 
-    let divisor = &GEN_POLYNOMIALS[ecc_per_block][..ecc_per_block];
+    let encoder = Encoder::get(ecc_per_block);
+    let mut ec_codewords = [0; 30];
+    let ec_codewords = &mut ec_codewords[..ecc_per_block];
 
-    for i in 0..group_1_blocks {
-        let start = i * data_per_g1_block;
-        let ec_codewords = remainder(&byte_vec[(start)..(start + data_per_g1_block)], divisor);
+    let mut start = 0;
+    for i in 0..blocks {
+        let len = if i < group_1_blocks {
+            data_per_g1_block
+        } else {
+            data_per_g2_block
+        };
+        encoder.encode(&byte_vec[start..start + len], ec_codewords);
+        start += len;
 
-        for j in 0..ec_codewords.len() {
-            interleaved[num_data_codewords + j * blocks + i] = ec_codewords[j];
+        for (j, &ec_codeword) in ec_codewords.iter().enumerate() {
+            interleaved[num_data_codewords + j * blocks + i] = ec_codeword;
         }
     }
 
-    let group_2_start = group_1_blocks * data_per_g1_block;
+    interleaved
+}
+
+/// Micro QR has a single block, so nothing is interleaved.
+/// M1 and M3 end with a 4 bit data codeword, which is followed directly by error correction.
+pub fn micro_ecc_and_sequence(mut data: Data) -> Vec<u8> {
+    let version = data.version.0;
+    let num_data_bits = MICRO_DATA_BITS[version][data.ecl as usize] as usize;
+    let num_ec_codewords = MICRO_EC_CODEWORDS[version][data.ecl as usize] as usize;
+
+    // terminator is 3, 5, 7, 9 bits for M1 - M4
+    let term_len = (num_data_bits - data.bits.len()).min(version * 2 + 1);
+    data.bits.push_n(0, term_len);
 
-    for i in 0..group_2_blocks {
-        let start = group_2_start + i * data_per_g2_block;
-        let ec_codewords = remainder(&byte_vec[(start)..(start + data_per_g2_block)], divisor);
+    // byte align, 4 bit codeword is already aligned
+    let byte_pad = ((8 - (data.bits.len() % 8)) % 8).min(num_data_bits - data.bits.len());
+    data.bits.push_n(0, byte_pad);
+
+    // fill data capacity, 4 bit codeword is filled with 0
+    let mut alternating_byte = 0b1110_1100;
+    while num_data_bits - data.bits.len() >= 8 {
+        data.bits.push_n(alternating_byte, 8);
+        alternating_byte ^= 0b1111_1101;
+    }
+    data.bits.push_n(0, num_data_bits - data.bits.len());
+
+    // 4 bit codeword is the high nibble of the last byte
+    let mut ec_codewords = [0; 30];
+    let ec_codewords = &mut ec_codewords[..num_ec_codewords];
+    Encoder::get(num_ec_codewords).encode(data.bits.as_ref(), ec_codewords);
+    for &ec_codeword in ec_codewords.iter() {
+        data.bits.push_n(ec_codeword.into(), 8);
+    }
+
+    data.bits.to_bytes()
+}
 
-        for j in 0..ec_codewords.len() {
-            interleaved[num_data_codewords + j * blocks + i + group_1_blocks] = ec_codewords[j];
+/// Reed-Solomon encoder for a fixed number of error correction codewords.
+///
+/// Row `f` of the table is the generator multiplied by `f`,
+/// so each data codeword costs a table lookup, a shift, and an XOR.
+#[derive(Debug)]
+pub struct Encoder {
+    num_ec_codewords: usize,
+    table: [[u8; 30]; 256],
+}
+
+static ENCODERS: [OnceLock<Encoder>; 31] = [const { OnceLock::new() }; 31];
+
+impl Encoder {
+    /// Built on first use, then cached. `num_ec_codewords` is 1 - 30
+    pub fn get(num_ec_codewords: usize) -> &'static Encoder {
+        ENCODERS[num_ec_codewords].get_or_init(|| Encoder::new(num_ec_codewords))
+    }
+
+    fn new(num_ec_codewords: usize) -> Self {
+        let generator = &GEN_POLYNOMIALS[num_ec_codewords][..num_ec_codewords];
+        let mut table = [[0; 30]; 256];
+        for (factor, row) in table.iter_mut().enumerate().skip(1) {
+            for (product, &coeff) in row.iter_mut().zip(generator) {
+                *product = (Gf(factor as u8) * Gf::exp(coeff as usize)).0;
+            }
+        }
+        Encoder {
+            num_ec_codewords,
+            table,
         }
     }
 
-    interleaved
+    /// Writes the error correction codewords for `data` to `ec_codewords`,
+    /// which must be `num_ec_codewords` long
+    pub fn encode(&self, data: &[u8], ec_codewords: &mut [u8]) {
+        // unused high registers stay 0, b/c the table is 0 past the generator
+        let mut registers = [0; 30];
+        for &codeword in data {
+            let row = &self.table[(codeword ^ registers[0]) as usize];
+            registers.copy_within(1.., 0);
+            registers[29] = 0;
+            for (register, product) in registers.iter_mut().zip(row) {
+                *register ^= product;
+            }
+        }
+        ec_codewords.copy_from_slice(&registers[..self.num_ec_codewords]);
+    }
 }
 
-// todo
-// benchmark potential optimizations
+/// Polynomial long division, see `Encoder` for a faster version with generators from `GEN_POLYNOMIALS`
 pub fn remainder(data: &[u8], generator: &[u8]) -> Vec<u8> {
     let num_codewords = generator.len();
     let mut base = [0; 123 + 30];
@@ -95,14 +202,200 @@ pub fn remainder(data: &[u8], generator: &[u8]) -> Vec<u8> {
     base[..data.len()].copy_from_slice(data);
 
     for i in 0..data.len() {
-        if base[i] == 0 {
+        let Some(alpha_diff) = Gf(base[i]).log() else {
             continue;
-        }
-        let alpha_diff = LOG_TABLE[base[i] as usize];
+        };
         for j in 0..num_codewords {
-            base[i + j + 1] ^= EXP_TABLE[(generator[j] as usize + alpha_diff as usize) % 255];
+            base[i + j + 1] ^= Gf::exp(generator[j] as usize + alpha_diff).0;
         }
     }
 
     base[data.len()..(data.len() + num_codewords)].to_vec()
 }
+
+/// Corrects a block of data codewords followed by `num_ec_codewords` error correction codewords.
+///
+/// Returns the number of corrected codewords, or None if there are too many errors.
+pub fn correct(block: &mut [u8], num_ec_codewords: usize) -> Option<usize> {
+    correct_with_erasures(block, num_ec_codewords, &[])
+}
+
+/// Same as `correct`, but codewords at the indices in `erasures` are known to be unreliable.
+///
+/// Up to `2 * errors + erasures <= num_ec_codewords` can be corrected, so an erasure costs half
+/// as much as an unknown error. Blocks can be up to 255 codewords, and don't need to be from a QR code.
+pub fn correct_with_erasures(
+    block: &mut [u8],
+    num_ec_codewords: usize,
+    erasures: &[usize],
+) -> Option<usize> {
+    let len = block.len();
+    if len > 255 || num_ec_codewords > len || erasures.len() > num_ec_codewords {
+        return None;
+    }
+    if erasures.iter().any(|&i| i >= len) {
+        return None;
+    }
+
+    // S_j = r(a^j), b/c generator roots are a^0 to a^(n - 1)
+    let received = Poly::from_high_first(block);
+    let syndromes: Vec<Gf> = (0..num_ec_codewords)
+        .map(|j| received.eval(Gf::exp(j)))
+        .collect();
+    if syndromes.iter().all(|&s| s == Gf::ZERO) {
+        return Some(0);
+    }
+
+    // codeword i is the coefficient of x^(len - 1 - i), so its locator is a^(len - 1 - i)
+    let locator_of = |i: usize| Gf::exp(len - 1 - i);
+    let inverse_of = |i: usize| Gf::exp(255 - (len - 1 - i) % 255);
+
+    // erasure locator is product of (1 + X_k * x)
+    let erasure_locator = erasures.iter().fold(Poly::new(vec![Gf::ONE]), |poly, &i| {
+        &poly * &Poly::new(vec![Gf::ONE, locator_of(i)])
+    });
+
+    // Berlekamp-Massey, starting from the erasures
+    let num_erasures = erasures.len();
+    let mut locator = erasure_locator.clone();
+    let mut prev_locator = erasure_locator;
+    let mut num_errors = num_erasures;
+    let mut shift = 1;
+    let mut prev_discrepancy = Gf::ONE;
+
+    for k in num_erasures..num_ec_codewords {
+        let mut discrepancy = Gf::ZERO;
+        for i in 0..locator.coeffs().len().min(k + 1) {
+            discrepancy += locator.coeff(i) * syndromes[k - i];
+        }
+        if discrepancy == Gf::ZERO {
+            shift += 1;
+            continue;
+        }
+
+        let coef = discrepancy / prev_discrepancy;
+        let next = &locator + &(&prev_locator * &Poly::monomial(coef, shift));
+
+        if 2 * num_errors <= k + num_erasures {
+            prev_locator = locator;
+            num_errors = k + 1 + num_erasures - num_errors;
+            prev_discrepancy = discrepancy;
+            shift = 1;
+        } else {
+            shift += 1;
+        }
+        locator = next;
+    }
+
+    // num_errors includes erasures here
+    if 2 * num_errors > num_ec_codewords + num_erasures {
+        return None;
+    }
+
+    // Chien search
+    let positions: Vec<usize> = (0..len)
+        .filter(|&i| locator.eval(inverse_of(i)) == Gf::ZERO)
+        .collect();
+    if positions.len() != num_errors {
+        return None;
+    }
+
+    // Forney, error evaluator is S(x) * locator(x) mod x^num_ec_codewords
+    let evaluator = (&Poly::new(syndromes) * &locator).truncate(num_ec_codewords);
+    let derivative = locator.derivative();
+
+    let mut corrected = 0;
+    for i in positions {
+        let denominator = derivative.eval(inverse_of(i));
+        if denominator == Gf::ZERO {
+            return None;
+        }
+        let magnitude = locator_of(i) * (evaluator.eval(inverse_of(i)) / denominator);
+        // erased codewords can turn out to be correct
+        if magnitude != Gf::ZERO {
+            block[i] ^= magnitude.0;
+            corrected += 1;
+        }
+    }
+
+    Some(corrected)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn encode(data: &[u8], num_ec_codewords: usize) -> Vec<u8> {
+        let divisor = &GEN_POLYNOMIALS[num_ec_codewords][..num_ec_codewords];
+        let mut block = data.to_vec();
+        block.extend(remainder(data, divisor));
+        block
+    }
+
+    #[test]
+    fn encoder_works() {
+        let data: Vec<u8> = (0..123u8).map(|i| i.wrapping_mul(37) ^ 5).collect();
+        for num_ec_codewords in [2, 7, 10, 17, 22, 30] {
+            let divisor = &GEN_POLYNOMIALS[num_ec_codewords][..num_ec_codewords];
+            let mut ec_codewords = vec![0; num_ec_codewords];
+            for len in [0, 1, 15, 123] {
+                Encoder::get(num_ec_codewords).encode(&data[..len], &mut ec_codewords);
+                assert_eq!(ec_codewords, remainder(&data[..len], divisor));
+            }
+        }
+    }
+
+    #[test]
+    fn correct_works() {
+        let data: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(7) ^ 3).collect();
+        let block = encode(&data, 20);
+
+        let mut received = block.clone();
+        assert_eq!(correct(&mut received, 20), Some(0));
+
+        for i in 0..10 {
+            received[i * 6] ^= 0x5A + i as u8;
+        }
+        assert_eq!(correct(&mut received, 20), Some(10));
+        assert_eq!(received, block);
+
+        received[0] ^= 1;
+        for i in 0..10 {
+            received[i * 5 + 1] ^= 0xFF;
+        }
+        assert_eq!(correct(&mut received, 20), None);
+    }
+
+    #[test]
+    fn correct_with_erasures_works() {
+        let data: Vec<u8> = (0..40u8).map(|i| i.wrapping_mul(13) ^ 1).collect();
+        let block = encode(&data, 20);
+
+        // 20 erasures, twice as many as errors alone
+        let erasures: Vec<usize> = (0..20).map(|i| i * 3).collect();
+        let mut received = block.clone();
+        for &i in erasures.iter() {
+            received[i] = 0;
+        }
+        let corrected = correct_with_erasures(&mut received, 20, &erasures).unwrap();
+        assert_eq!(received, block);
+        assert_eq!(
+            corrected,
+            erasures.iter().filter(|&&i| block[i] != 0).count()
+        );
+
+        // 2 errors + 16 erasures
+        let erasures: Vec<usize> = (0..16).map(|i| i * 2 + 20).collect();
+        let mut received = block.clone();
+        for &i in erasures.iter() {
+            received[i] ^= 0x33;
+        }
+        received[1] ^= 0x80;
+        received[55] ^= 0x01;
+        assert!(correct_with_erasures(&mut received, 20, &erasures).is_some());
+        assert_eq!(received, block);
+
+        assert_eq!(correct(&mut block.clone(), 20), Some(0));
+        assert_eq!(correct_with_erasures(&mut block.clone(), 20, &[60]), None);
+    }
+}
@@ -0,0 +1,249 @@
+// field arithmetic is built from XOR and log/exp tables
+#![allow(clippy::suspicious_arithmetic_impl, clippy::suspicious_op_assign_impl)]
+
+use std::ops::{Add, AddAssign, Div, Mul, MulAssign, Sub};
+
+use crate::math::{EXP_TABLE, LOG_TABLE};
+
+/// Element of GF(256) with the QR code polynomial x^8 + x^4 + x^3 + x^2 + 1.
+///
+/// Addition and subtraction are both XOR.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Default, Debug)]
+pub struct Gf(pub u8);
+
+impl Gf {
+    pub const ZERO: Gf = Gf(0);
+    pub const ONE: Gf = Gf(1);
+
+    /// a^i, where a = 2 generates every nonzero element
+    pub fn exp(i: usize) -> Gf {
+        Gf(EXP_TABLE[i % 255])
+    }
+    /// log_a, None for 0
+    pub fn log(self) -> Option<usize> {
+        if self == Gf::ZERO {
+            return None;
+        }
+        Some(LOG_TABLE[self.0 as usize] as usize)
+    }
+    /// None for 0
+    pub fn inv(self) -> Option<Gf> {
+        self.log().map(|log| Gf::exp(255 - log))
+    }
+    /// 0^0 is 1
+    pub fn pow(self, n: usize) -> Gf {
+        match self.log() {
+            Some(log) => Gf::exp(log * n % 255),
+            None if n == 0 => Gf::ONE,
+            None => Gf::ZERO,
+        }
+    }
+}
+
+impl Add for Gf {
+    type Output = Gf;
+
+    fn add(self, rhs: Gf) -> Gf {
+        Gf(self.0 ^ rhs.0)
+    }
+}
+
+impl AddAssign for Gf {
+    fn add_assign(&mut self, rhs: Gf) {
+        self.0 ^= rhs.0;
+    }
+}
+
+impl Sub for Gf {
+    type Output = Gf;
+
+    fn sub(self, rhs: Gf) -> Gf {
+        Gf(self.0 ^ rhs.0)
+    }
+}
+
+impl Mul for Gf {
+    type Output = Gf;
+
+    fn mul(self, rhs: Gf) -> Gf {
+        match (self.log(), rhs.log()) {
+            (Some(a), Some(b)) => Gf::exp(a + b),
+            _ => Gf::ZERO,
+        }
+    }
+}
+
+impl MulAssign for Gf {
+    fn mul_assign(&mut self, rhs: Gf) {
+        *self = *self * rhs;
+    }
+}
+
+impl Div for Gf {
+    type Output = Gf;
+
+    /// Panics if `rhs` is 0
+    fn div(self, rhs: Gf) -> Gf {
+        self * rhs.inv().expect("division by zero in GF(256)")
+    }
+}
+
+/// Polynomial over GF(256), coefficients are lowest degree first without trailing zeros
+#[derive(Clone, PartialEq, Eq, Hash, Default, Debug)]
+pub struct Poly(Vec<Gf>);
+
+impl Poly {
+    pub fn new(mut coeffs: Vec<Gf>) -> Self {
+        while coeffs.last() == Some(&Gf::ZERO) {
+            coeffs.pop();
+        }
+        Poly(coeffs)
+    }
+    /// Coefficients highest degree first, which is the order of codewords in a block
+    pub fn from_high_first(coeffs: &[u8]) -> Self {
+        Poly::new(coeffs.iter().rev().map(|&c| Gf(c)).collect())
+    }
+    /// `coeff * x^degree`
+    pub fn monomial(coeff: Gf, degree: usize) -> Self {
+        let mut coeffs = vec![Gf::ZERO; degree + 1];
+        coeffs[degree] = coeff;
+        Poly::new(coeffs)
+    }
+    /// Reed-Solomon generator with roots a^0 to a^(n - 1), see `GEN_POLYNOMIALS`
+    pub fn generator(n: usize) -> Self {
+        (0..n).fold(Poly::new(vec![Gf::ONE]), |poly, i| {
+            &poly * &Poly::new(vec![Gf::exp(i), Gf::ONE])
+        })
+    }
+
+    pub fn coeffs(&self) -> &[Gf] {
+        &self.0
+    }
+    /// 0 past the highest degree
+    pub fn coeff(&self, degree: usize) -> Gf {
+        self.0.get(degree).copied().unwrap_or(Gf::ZERO)
+    }
+    /// None for the zero polynomial
+    pub fn degree(&self) -> Option<usize> {
+        self.0.len().checked_sub(1)
+    }
+    pub fn is_zero(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    pub fn eval(&self, x: Gf) -> Gf {
+        self.0.iter().rev().fold(Gf::ZERO, |acc, &c| acc * x + c)
+    }
+    /// Quotient and remainder, panics if `divisor` is zero
+    pub fn div_rem(&self, divisor: &Poly) -> (Poly, Poly) {
+        let divisor_degree = divisor.degree().expect("division by zero polynomial");
+        let lead_inv = divisor.0[divisor_degree].inv().unwrap();
+
+        let mut rem = self.0.clone();
+        let quotient_len = (rem.len() + 1).saturating_sub(divisor.0.len());
+        let mut quotient = vec![Gf::ZERO; quotient_len];
+        for i in (0..quotient_len).rev() {
+            let coeff = rem[i + divisor_degree] * lead_inv;
+            quotient[i] = coeff;
+            for (j, &d) in divisor.0.iter().enumerate() {
+                rem[i + j] += coeff * d;
+            }
+        }
+        (Poly::new(quotient), Poly::new(rem))
+    }
+    /// Formal derivative, even powers vanish b/c 2 = 0
+    pub fn derivative(&self) -> Poly {
+        let coeffs = self.0.iter().enumerate().skip(1);
+        Poly::new(
+            coeffs
+                .map(|(i, &c)| if i % 2 == 1 { c } else { Gf::ZERO })
+                .collect(),
+        )
+    }
+    /// Remainder mod x^n
+    pub fn truncate(&self, n: usize) -> Poly {
+        Poly::new(self.0.iter().take(n).copied().collect())
+    }
+}
+
+impl Add for &Poly {
+    type Output = Poly;
+
+    fn add(self, rhs: &Poly) -> Poly {
+        let len = self.0.len().max(rhs.0.len());
+        Poly::new((0..len).map(|i| self.coeff(i) + rhs.coeff(i)).collect())
+    }
+}
+
+impl Mul for &Poly {
+    type Output = Poly;
+
+    fn mul(self, rhs: &Poly) -> Poly {
+        if self.is_zero() || rhs.is_zero() {
+            return Poly::default();
+        }
+        let mut coeffs = vec![Gf::ZERO; self.0.len() + rhs.0.len() - 1];
+        for (i, &a) in self.0.iter().enumerate() {
+            for (j, &b) in rhs.0.iter().enumerate() {
+                coeffs[i + j] += a * b;
+            }
+        }
+        Poly::new(coeffs)
+    }
+}
+
+impl Mul<Gf> for &Poly {
+    type Output = Poly;
+
+    fn mul(self, rhs: Gf) -> Poly {
+        Poly::new(self.0.iter().map(|&c| c * rhs).collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::GEN_POLYNOMIALS;
+
+    #[test]
+    fn gf_works() {
+        for a in 1..=255 {
+            let a = Gf(a);
+            assert_eq!(a * a.inv().unwrap(), Gf::ONE);
+            assert_eq!(a / a, Gf::ONE);
+            assert_eq!(a.pow(3), a * a * a);
+            assert_eq!(Gf::exp(a.log().unwrap()), a);
+        }
+        assert_eq!(Gf(0x80) * Gf(2), Gf(0x1D));
+        assert_eq!(Gf::ZERO.inv(), None);
+        assert_eq!(Gf::ZERO.pow(0), Gf::ONE);
+    }
+
+    #[test]
+    fn poly_works() {
+        for n in 1..=30 {
+            // GEN_POLYNOMIALS is highest degree first in log form, without the leading 1
+            let generator = Poly::generator(n);
+            let expected: Vec<Gf> = GEN_POLYNOMIALS[n][..n]
+                .iter()
+                .rev()
+                .map(|&log| Gf::exp(log as usize))
+                .chain([Gf::ONE])
+                .collect();
+            assert_eq!(generator.coeffs(), expected);
+            assert_eq!(generator.eval(Gf::exp(n - 1)), Gf::ZERO);
+        }
+
+        let a = Poly::from_high_first(&[3, 0, 7, 200, 1]);
+        let b = Poly::from_high_first(&[5, 9, 1]);
+        let (quotient, rem) = (&(&a * &b) + &Poly::new(vec![Gf(4)])).div_rem(&b);
+        assert_eq!(quotient, a);
+        assert_eq!(rem, Poly::new(vec![Gf(4)]));
+
+        // (x + 1)^2 = x^2 + 1, so the derivative is 0
+        let square = &Poly::generator(1) * &Poly::generator(1);
+        assert_eq!(square, Poly::new(vec![Gf::ONE, Gf::ZERO, Gf::ONE]));
+        assert!(square.derivative().is_zero());
+        assert_eq!(b.derivative(), Poly::new(vec![Gf(9)]));
+    }
+}
@@ -1,16 +1,23 @@
 pub mod constants;
+pub mod gf256;
 pub mod math;
+pub mod shift_jis;
 
 pub mod data;
 pub mod encoding;
 pub mod error_correction;
+pub mod segment;
 
 pub mod mask;
 pub mod matrix;
 pub mod qr_code;
+pub mod template;
 
 pub mod bit_info;
+pub mod decode;
 pub mod qart;
+pub mod scan;
+pub mod weights;
 
 pub mod render;
 
@@ -18,10 +25,13 @@ pub mod render;
 mod wasm;
 
 use crate::data::Data;
-use crate::qr_code::{Mask, Mode, Version, ECL};
-use encoding::encoding_mode;
-use qart::{Qart, WeightPixel};
+use crate::qr_code::{Eci, Fnc1, Mask, Mode, Symbol, Version, ECL};
+use encoding::escape_fnc1;
+use mask::MaskSelector;
+use qart::{Qart, QartReport, QartSequence, WeightPixel};
 use qr_code::QrCode;
+use segment::Segment;
+use weights::{Image, ImageWeights};
 
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
@@ -29,10 +39,14 @@ use wasm_bindgen::prelude::*;
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Debug)]
 pub struct QrOptions {
+    symbol: Option<Symbol>,
     min_version: Version,
     min_ecl: ECL,
     mode: Option<Mode>,
+    eci: Option<Eci>,
+    fnc1: Option<Fnc1>,
     mask: Option<Mask>,
+    mask_selector: Option<Box<dyn MaskSelector>>,
     strict_version: bool,
     strict_ecl: bool,
 }
@@ -42,14 +56,26 @@ impl QrOptions {
     #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
     pub fn new() -> Self {
         QrOptions {
+            symbol: Some(Symbol::Qr),
             min_version: Version(1),
             strict_version: false,
             min_ecl: ECL::Low,
             strict_ecl: false,
             mode: None,
+            eci: None,
+            fnc1: None,
             mask: None,
+            mask_selector: None,
         }
     }
+    /// None picks the smallest symbol that fits, trying Micro QR before QR. rMQR is never picked.
+    /// Ignored by `generate_qart` and `generate_structured`, which are QR only.
+    pub fn symbol(mut self, symbol: Option<Symbol>) -> Self {
+        self.symbol = symbol;
+        self
+    }
+    /// For Micro QR, version is 1 - 4 for M1 - M4.
+    /// For rMQR, version is 1 - 32 for R7x43 - R17x139, see `RMQR_SIZES`
     pub fn min_version(mut self, version: Version) -> Self {
         self.min_version = version;
         self
@@ -62,6 +88,13 @@ impl QrOptions {
         self.mode = mode;
         self
     }
+    /// None adds a UTF-8 ECI header only if Byte mode input is not ASCII
+    pub fn eci(mut self, eci: Option<Eci>) -> Self {
+        self.eci = eci;
+        self
+    }
+    /// Micro QR only has `M0` - `M3`, so other masks are `QrError::InvalidEncoding` for Micro QR.
+    /// rMQR ignores this, it only has one mask.
     pub fn mask(mut self, mask: Option<Mask>) -> Self {
         self.mask = mask;
         self
@@ -76,26 +109,142 @@ impl QrOptions {
     }
 }
 
+impl QrOptions {
+    /// GS separators (0x1D) in the input will be encoded as `%` in Alphanumeric mode
+    pub fn fnc1(mut self, fnc1: Option<Fnc1>) -> Self {
+        self.fnc1 = fnc1;
+        self
+    }
+    /// Used when mask is None, defaults to `IsoPenalty`
+    pub fn mask_selector(mut self, selector: impl MaskSelector + 'static) -> Self {
+        self.mask_selector = Some(Box::new(selector));
+        self
+    }
+    fn new_qr_code(&self, data: Data) -> QrCode {
+        match &self.mask_selector {
+            Some(selector) => QrCode::with_selector(data, self.mask, selector.as_ref()),
+            None => QrCode::new(data, self.mask),
+        }
+    }
+}
+
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum QrError {
     InvalidEncoding,
     ExceedsMaxCapacity,
+    /// Structured Append needs 1 - 16 symbols, and no more than input characters
+    InvalidSymbolCount,
 }
 
 pub fn generate(input: &str, qr_options: &QrOptions) -> Result<QrCode, QrError> {
-    match resolve_data(input, qr_options) {
-        Ok(data) => Ok(QrCode::new(data, qr_options.mask)),
+    generate_bytes(input.as_bytes(), qr_options)
+}
+
+/// Same as `generate`, but input doesn't need to be valid UTF-8
+pub fn generate_bytes(input: &[u8], qr_options: &QrOptions) -> Result<QrCode, QrError> {
+    let data = match qr_options.symbol {
+        Some(symbol) => resolve_data(input, &[], symbol, qr_options),
+        None => resolve_data(input, &[], Symbol::Micro, qr_options)
+            .or_else(|_| resolve_data(input, &[], Symbol::Qr, qr_options)),
+    };
+    match data {
+        Ok(data) => Ok(qr_options.new_qr_code(data)),
         Err(err) => Err(err),
     }
 }
 
+/// Splits input across up to 16 QR codes using Structured Append.
+///
+/// If `symbols` is None, the fewest symbols that fit are used.
+/// Otherwise, input is split evenly into exactly that many symbols,
+/// which can be combined with `strict_version` to get symbols of a fixed size.
+pub fn generate_structured(
+    input: &str,
+    symbols: Option<usize>,
+    qr_options: &QrOptions,
+) -> Result<Vec<QrCode>, QrError> {
+    generate_structured_bytes(input.as_bytes(), symbols, qr_options)
+}
+
+/// Same as `generate_structured`, but input doesn't need to be valid UTF-8
+pub fn generate_structured_bytes(
+    input: &[u8],
+    symbols: Option<usize>,
+    qr_options: &QrOptions,
+) -> Result<Vec<QrCode>, QrError> {
+    // splitting chars would make each symbol invalid UTF-8
+    let boundaries: Vec<usize> = match std::str::from_utf8(input) {
+        Ok(text) => text
+            .char_indices()
+            .map(|(i, _)| i)
+            .chain([input.len()])
+            .collect(),
+        Err(_) => (0..=input.len()).collect(),
+    };
+    // every symbol gets at least one char, except for empty input
+    let chars = (boundaries.len() - 1).max(1);
+
+    let totals = match symbols {
+        Some(total) if (1..=16).contains(&total) && total <= chars => total..=total,
+        Some(_) => return Err(QrError::InvalidSymbolCount),
+        None => 1..=chars.min(16),
+    };
+
+    for total in totals {
+        let resolve = |parity: u8| -> Result<Vec<Data>, QrError> {
+            (0..total)
+                .map(|index| {
+                    let start = boundaries[(boundaries.len() - 1) * index / total];
+                    let end = boundaries[(boundaries.len() - 1) * (index + 1) / total];
+                    let header = if total > 1 {
+                        vec![Segment::StructuredAppend {
+                            index: index as u8,
+                            total: total as u8,
+                            parity,
+                        }]
+                    } else {
+                        Vec::new()
+                    };
+                    resolve_data(&input[start..end], &header, Symbol::Qr, qr_options)
+                })
+                .collect()
+        };
+        let symbols = match resolve(0) {
+            Ok(symbols) => symbols,
+            Err(QrError::ExceedsMaxCapacity) => continue,
+            Err(err) => return Err(err),
+        };
+
+        // parity is over encoded bytes, so it depends on which chars end up as Kanji.
+        // The header is the same size for any parity, so resolving again splits the same way.
+        let parity = symbols
+            .iter()
+            .flat_map(|data| &data.segments)
+            .fold(0, |parity, segment| parity ^ segment.parity());
+        let symbols = if total > 1 && parity != 0 {
+            resolve(parity)?
+        } else {
+            symbols
+        };
+
+        return Ok(symbols
+            .into_iter()
+            .map(|data| qr_options.new_qr_code(data))
+            .collect());
+    }
+
+    Err(QrError::ExceedsMaxCapacity)
+}
+
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum QartError {
     InvalidEncoding,
     ExceedsMaxCapacity,
     InvalidPixelWeights,
+    /// Only from converting `QrError::InvalidSymbolCount`, QArt is a single symbol
+    InvalidSymbolCount,
 }
 
 impl From<QrError> for QartError {
@@ -103,6 +252,7 @@ impl From<QrError> for QartError {
         match value {
             QrError::InvalidEncoding => QartError::InvalidEncoding,
             QrError::ExceedsMaxCapacity => QartError::ExceedsMaxCapacity,
+            QrError::InvalidSymbolCount => QartError::InvalidSymbolCount,
         }
     }
 }
@@ -112,7 +262,16 @@ pub fn generate_qart(
     qr_options: &QrOptions,
     pixel_weights: &[WeightPixel],
 ) -> Result<QrCode, QartError> {
-    let data = match resolve_data(input, qr_options) {
+    generate_qart_bytes(input.as_bytes(), qr_options, pixel_weights)
+}
+
+/// Same as `generate_qart`, but input doesn't need to be valid UTF-8
+pub fn generate_qart_bytes(
+    input: &[u8],
+    qr_options: &QrOptions,
+    pixel_weights: &[WeightPixel],
+) -> Result<QrCode, QartError> {
+    let data = match resolve_data(input, &[], Symbol::Qr, qr_options) {
         Ok(data) => data,
         Err(err) => return Err(err.into()),
     };
@@ -127,32 +286,543 @@ pub fn generate_qart(
     Ok(qr_code)
 }
 
-fn resolve_data(input: &str, qr_options: &QrOptions) -> Result<Data, QrError> {
-    let mut mode = Mode::Byte;
+/// QArt codes for each frame of `frames`, which change as little as possible between frames.
+///
+/// Version, ECL, and mask (`M0` if None) are picked once, and every frame decodes to `input`.
+/// Each frame must be `pixel_weights` for that version, see `QartSequence`.
+pub fn generate_qart_sequence<I>(
+    input: &str,
+    qr_options: &QrOptions,
+    frames: I,
+) -> Result<QartSequence<I::IntoIter>, QartError>
+where
+    I: IntoIterator,
+    I::Item: AsRef<[WeightPixel]>,
+{
+    let data = resolve_data(input.as_bytes(), &[], Symbol::Qr, qr_options)?;
+    let mask = qr_options.mask.unwrap_or(Mask::M0);
+    Ok(QartSequence::new(data, mask, frames.into_iter()))
+}
+
+/// Same as `generate_qart`, but `pixel_weights` come from `image` at the chosen version
+pub fn generate_qart_image(
+    input: &str,
+    qr_options: &QrOptions,
+    image: &Image,
+    image_weights: &ImageWeights,
+) -> Result<QrCode, QartError> {
+    if !image.is_valid() {
+        return Err(QartError::InvalidPixelWeights);
+    }
+    let data = resolve_data(input.as_bytes(), &[], Symbol::Qr, qr_options)?;
+
+    let pixel_weights = image_weights.weights(image, data.version);
+    let qart = Qart::new(data, qr_options.mask.unwrap_or(Mask::M0));
+    Ok(qart.to_qr_code(&pixel_weights))
+}
+
+/// Same as `generate_qart_image`, but picks the closest match to `image` among every mask,
+/// every ECL from `min_ecl`, and every version from `min_version` to `max_version`.
+///
+/// `strict_version`, `strict_ecl`, and `mask` limit the search to that value.
+/// Ties go to the smaller version, then the higher ECL.
+pub fn generate_qart_search(
+    input: &str,
+    qr_options: &QrOptions,
+    image: &Image,
+    image_weights: &ImageWeights,
+    max_version: Version,
+) -> Result<(QrCode, QartReport), QartError> {
+    if !image.is_valid() {
+        return Err(QartError::InvalidPixelWeights);
+    }
+    let max_version = if qr_options.strict_version {
+        qr_options.min_version
+    } else {
+        max_version.max(qr_options.min_version)
+    };
+    let ecls = [ECL::High, ECL::Quartile, ECL::Medium, ECL::Low]
+        .into_iter()
+        .filter(|&ecl| {
+            if qr_options.strict_ecl {
+                ecl == qr_options.min_ecl
+            } else {
+                ecl >= qr_options.min_ecl
+            }
+        });
+    let masks = match qr_options.mask {
+        Some(mask) => vec![mask],
+        None => vec![
+            Mask::M0,
+            Mask::M1,
+            Mask::M2,
+            Mask::M3,
+            Mask::M4,
+            Mask::M5,
+            Mask::M6,
+            Mask::M7,
+        ],
+    };
+
+    let mut best: Option<(QrCode, QartReport)> = None;
+    let mut error = QartError::ExceedsMaxCapacity;
+    for version in qr_options.min_version.0..=max_version.0 {
+        let pixel_weights = image_weights.weights(image, Version(version));
+        for ecl in ecls.clone() {
+            let candidate_options = QrOptions::new()
+                .mode(qr_options.mode)
+                .eci(qr_options.eci)
+                .fnc1(qr_options.fnc1)
+                .min_version(Version(version))
+                .strict_version(true)
+                .min_ecl(ecl)
+                .strict_ecl(true);
+            let data = match resolve_data(input.as_bytes(), &[], Symbol::Qr, &candidate_options) {
+                Ok(data) => data,
+                Err(err) => {
+                    if err == QrError::InvalidEncoding {
+                        error = QartError::InvalidEncoding;
+                    }
+                    continue;
+                }
+            };
+            for &mask in masks.iter() {
+                let (qr_code, report) =
+                    Qart::new(data.clone(), mask).to_qr_code_with_report(&pixel_weights);
+                if best
+                    .as_ref()
+                    .is_none_or(|(_, best)| report.is_better_than(best))
+                {
+                    best = Some((qr_code, report));
+                }
+            }
+        }
+    }
+    best.ok_or(error)
+}
+
+/// Segments in `header` are placed before any ECI or FNC1 header and the input
+fn resolve_data(
+    input: &[u8],
+    header: &[Segment],
+    symbol: Symbol,
+    qr_options: &QrOptions,
+) -> Result<Data, QrError> {
+    if symbol != Symbol::Qr {
+        return resolve_micro_data(input, symbol, qr_options);
+    }
+
+    // scanners assume ISO-8859-1 without a header, but binary data shouldn't claim to be UTF-8
+    let is_utf8 = std::str::from_utf8(input).is_ok();
+
+    let with_header = |eci: Option<Eci>| {
+        let mut segments = header.to_vec();
+        if let Some(eci) = eci {
+            segments.push(Segment::Eci(eci));
+        }
+        if let Some(fnc1) = qr_options.fnc1 {
+            segments.push(Segment::Fnc1(fnc1));
+        }
+        segments
+    };
+
+    let data = if let Some(specified) = qr_options.mode {
+        let segment = match qr_options.fnc1 {
+            Some(_) if specified == Mode::Alphanumeric => Segment::Alphanumeric(escape_fnc1(input)),
+            _ => Segment::from_bytes(specified, input),
+        };
+        if !segment.is_valid() {
+            return Err(QrError::InvalidEncoding);
+        }
+
+        let eci = match qr_options.eci {
+            Some(eci) => Some(eci),
+            None if specified == Mode::Byte && !input.is_ascii() && is_utf8 => Some(Eci::UTF8),
+            None => None,
+        };
+
+        let mut segments = with_header(eci);
+        segments.push(segment);
+
+        Data::from_segments(
+            segments,
+            qr_options.min_version,
+            qr_options.strict_version,
+            qr_options.min_ecl,
+            qr_options.strict_ecl,
+        )
+    } else {
+        let segmented = |eci: Option<Eci>| {
+            Data::segmented(
+                &with_header(eci),
+                input,
+                qr_options.min_version,
+                qr_options.strict_version,
+                qr_options.min_ecl,
+                qr_options.strict_ecl,
+            )
+        };
 
-    if let Some(specified) = qr_options.mode {
-        if specified != Mode::Byte {
-            let lowest = encoding_mode(input);
-            if (lowest as u8) > (specified as u8) {
-                return Err(QrError::InvalidEncoding);
+        match segmented(qr_options.eci) {
+            Some(data)
+                if qr_options.eci.is_none()
+                    && is_utf8
+                    && data
+                        .segments
+                        .iter()
+                        .any(|s| matches!(s, Segment::Byte(bytes) if !bytes.is_ascii())) =>
+            {
+                segmented(Some(Eci::UTF8))
             }
-            mode = specified;
+            data => data,
         }
+    };
+
+    match data {
+        Some(x) => Ok(x),
+        None => Err(QrError::ExceedsMaxCapacity),
+    }
+}
+
+/// Micro QR and rMQR have no headers, so UTF-8 input is never marked with an ECI
+fn resolve_micro_data(
+    input: &[u8],
+    symbol: Symbol,
+    qr_options: &QrOptions,
+) -> Result<Data, QrError> {
+    let rmqr = symbol == Symbol::Rmqr;
+    let from_segments = if rmqr {
+        Data::from_segments_rmqr
+    } else {
+        Data::from_segments_micro
+    };
+    let segmented = if rmqr {
+        Data::segmented_rmqr
     } else {
-        mode = encoding_mode(input);
+        Data::segmented_micro
+    };
+
+    if qr_options.eci.is_some() || qr_options.fnc1.is_some() {
+        return Err(QrError::InvalidEncoding);
+    }
+    if !rmqr && qr_options.mask.is_some_and(|mask| mask as u8 >= 4) {
+        return Err(QrError::InvalidEncoding);
     }
 
-    let data = Data::new_verbose(
-        input,
-        mode,
-        qr_options.min_version,
-        qr_options.strict_version,
-        qr_options.min_ecl,
-        qr_options.strict_ecl,
-    );
+    let data = if let Some(specified) = qr_options.mode {
+        let segment = Segment::from_bytes(specified, input);
+        if !segment.is_valid() {
+            return Err(QrError::InvalidEncoding);
+        }
+        from_segments(
+            vec![segment],
+            qr_options.min_version,
+            qr_options.strict_version,
+            qr_options.min_ecl,
+            qr_options.strict_ecl,
+        )
+    } else {
+        segmented(
+            input,
+            qr_options.min_version,
+            qr_options.strict_version,
+            qr_options.min_ecl,
+            qr_options.strict_ecl,
+        )
+    };
 
     match data {
         Some(x) => Ok(x),
         None => Err(QrError::ExceedsMaxCapacity),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        constants::{RMQR_DATA_MODULES, RMQR_SIZES},
+        matrix::{Matrix, Module},
+    };
+
+    #[test]
+    fn generate_qart_search_works() {
+        use crate::{
+            decode::decode,
+            weights::{PixelFormat, WeightScheme},
+        };
+
+        let input = "https://github.com/zhengkyl/fuqr";
+        // diagonal stripes, 8 pixels wide
+        let pixels: Vec<u8> = (0..64 * 64)
+            .map(|i| {
+                if (i % 64 + i / 64) / 8 % 2 == 0 {
+                    0
+                } else {
+                    255
+                }
+            })
+            .collect();
+        let image = Image::new(&pixels, 64, 64, PixelFormat::Luma);
+        let image_weights = ImageWeights::new().scheme(WeightScheme::Uniform(20));
+        let options = QrOptions::new().min_version(Version(3));
+
+        let (qr_code, report) =
+            generate_qart_search(input, &options, &image, &image_weights, Version(5)).unwrap();
+        assert_eq!(decode(&qr_code.matrix).unwrap().text(), input);
+        assert!((3..=5).contains(&qr_code.version.0));
+        assert!(report.total > 0 && report.mismatch < report.total);
+        assert!(report.fidelity() > 0.5);
+
+        // no single mask at the chosen version and ECL does better
+        for mask in [Mask::M0, Mask::M3, Mask::M7] {
+            let options = QrOptions::new()
+                .min_version(qr_code.version)
+                .strict_version(true)
+                .min_ecl(qr_code.ecl)
+                .strict_ecl(true)
+                .mask(Some(mask));
+            let (other, other_report) =
+                generate_qart_search(input, &options, &image, &image_weights, Version(40)).unwrap();
+            assert_eq!(other.mask, mask);
+            assert!(!other_report.is_better_than(&report));
+        }
+
+        let result = generate_qart_search(
+            &"a".repeat(200),
+            &options,
+            &image,
+            &image_weights,
+            Version(4),
+        );
+        assert_eq!(result.unwrap_err(), QartError::ExceedsMaxCapacity);
+    }
+
+    #[test]
+    fn structured_append_works() {
+        let input = "0123456789".repeat(1000);
+        let qr_codes = generate_structured(&input, None, &QrOptions::new()).unwrap();
+        assert_eq!(qr_codes.len(), 2);
+
+        let parity = input.bytes().fold(0, |parity, b| parity ^ b);
+        let mut decoded = String::new();
+        for (i, qr_code) in qr_codes.iter().enumerate() {
+            assert_eq!(
+                qr_code.segments[0],
+                Segment::StructuredAppend {
+                    index: i as u8,
+                    total: 2,
+                    parity,
+                }
+            );
+            for segment in &qr_code.segments[1..] {
+                match segment {
+                    Segment::Numeric(text) => decoded.push_str(text),
+                    _ => panic!("expected numeric"),
+                }
+            }
+        }
+        assert_eq!(decoded, input);
+
+        let qr_codes = generate_structured(
+            &input,
+            Some(8),
+            &QrOptions::new()
+                .min_version(Version(20))
+                .strict_version(true),
+        )
+        .unwrap();
+        assert_eq!(qr_codes.len(), 8);
+        assert!(qr_codes
+            .iter()
+            .all(|qr_code| qr_code.version == Version(20)));
+
+        let result = generate_structured(
+            &input,
+            Some(2),
+            &QrOptions::new()
+                .min_version(Version(20))
+                .strict_version(true),
+        );
+        assert_eq!(result.unwrap_err(), QrError::ExceedsMaxCapacity);
+
+        let qr_codes = generate_structured("hello", None, &QrOptions::new()).unwrap();
+        assert_eq!(qr_codes.len(), 1);
+        assert_eq!(qr_codes[0].segments, vec![Segment::Byte("hello".into())]);
+
+        for symbols in [Some(0), Some(17)] {
+            let result = generate_structured(&input, symbols, &QrOptions::new());
+            assert_eq!(result.unwrap_err(), QrError::InvalidSymbolCount);
+        }
+        assert_eq!(
+            QartError::from(QrError::InvalidSymbolCount),
+            QartError::InvalidSymbolCount
+        );
+        // parity is over Shift JIS bytes, 点 cancels out and 茗 is 0xE4AA, so 0xE4 ^ 0xAA
+        let qr_codes = generate_structured("点茗点", Some(2), &QrOptions::new()).unwrap();
+        for qr_code in qr_codes.iter() {
+            assert!(matches!(
+                qr_code.segments[0],
+                Segment::StructuredAppend { parity: 0x4E, .. }
+            ));
+            assert!(matches!(qr_code.segments[1], Segment::Kanji(_)));
+        }
+
+        // more symbols than chars would leave some empty
+        let result = generate_structured("😀", Some(3), &QrOptions::new());
+        assert_eq!(result.unwrap_err(), QrError::InvalidSymbolCount);
+        let qr_codes = generate_structured("😀😀😀", Some(3), &QrOptions::new()).unwrap();
+        for qr_code in qr_codes.iter() {
+            assert_eq!(
+                qr_code.segments.last().unwrap(),
+                &Segment::Byte("😀".into())
+            );
+        }
+    }
+
+    #[test]
+    fn micro_qr_works() {
+        // ISO 18004 Annex I example, M2-L
+        let qr_code = generate(
+            "01234567",
+            &QrOptions::new()
+                .symbol(Some(Symbol::Micro))
+                .strict_ecl(true),
+        )
+        .unwrap();
+        assert_eq!(qr_code.version, Version(2));
+        assert_eq!(qr_code.mask, Mask::M1);
+
+        let expected = "\
+            #######.#.#.#\
+            #.....#.###.#\
+            #.###.#..##.#\
+            #.###.#..####\
+            #.###.#.###..\
+            #.....#.#...#\
+            #######..####\
+            .........##..\
+            ##.#....#...#\
+            .##.#.#.#.#.#\
+            ###..#######.\
+            ...#.#....##.\
+            ###.#..##.###";
+        let actual: String = qr_code
+            .matrix
+            .value
+            .iter()
+            .map(|module| if module.has(Module::ON) { '#' } else { '.' })
+            .collect();
+        assert_eq!(actual, expected);
+
+        let qr_code = generate("1", &QrOptions::new().symbol(None)).unwrap();
+        assert_eq!(qr_code.symbol, Symbol::Micro);
+        assert_eq!(qr_code.version, Version(1));
+
+        // Micro QR has no M4 - M7
+        let options = QrOptions::new().mask(Some(Mask::M4));
+        let result = generate("1", &options.symbol(Some(Symbol::Micro)));
+        assert_eq!(result.unwrap_err(), QrError::InvalidEncoding);
+        let options = QrOptions::new().mask(Some(Mask::M4));
+        let qr_code = generate("1", &options.symbol(None)).unwrap();
+        assert_eq!((qr_code.symbol, qr_code.mask), (Symbol::Qr, Mask::M4));
+
+        let qr_code = generate("hello", &QrOptions::new().symbol(None)).unwrap();
+        assert_eq!(qr_code.symbol, Symbol::Micro);
+        assert_eq!(qr_code.version, Version(3));
+
+        let qr_code = generate(&"hello".repeat(10), &QrOptions::new().symbol(None)).unwrap();
+        assert_eq!(qr_code.symbol, Symbol::Qr);
+
+        let result = generate(
+            "hello",
+            &QrOptions::new()
+                .symbol(Some(Symbol::Micro))
+                .fnc1(Some(Fnc1::First)),
+        );
+        assert_eq!(result.unwrap_err(), QrError::InvalidEncoding);
+    }
+
+    #[test]
+    fn mask_selector_works() {
+        let target = generate("hello", &QrOptions::new().mask(Some(Mask::M5))).unwrap();
+        let qr_code = generate(
+            "hello",
+            &QrOptions::new().mask_selector(move |matrix: &Matrix<Module>| {
+                let diff = matrix.value.iter().zip(&target.matrix.value);
+                diff.filter(|(a, b)| a.has(Module::ON) != b.has(Module::ON))
+                    .count() as u32
+            }),
+        )
+        .unwrap();
+        assert_eq!(qr_code.mask, Mask::M5);
+
+        // given mask takes priority
+        let qr_code = generate(
+            "hello",
+            &QrOptions::new()
+                .mask(Some(Mask::M2))
+                .mask_selector(|_: &Matrix<Module>| 0),
+        )
+        .unwrap();
+        assert_eq!(qr_code.mask, Mask::M2);
+
+        let qr_code = generate(
+            "1",
+            &QrOptions::new()
+                .symbol(Some(Symbol::Micro))
+                .mask_selector(|matrix: &Matrix<Module>| matrix.get(1, 9).has(Module::ON) as u32),
+        )
+        .unwrap();
+        assert!(!qr_code.matrix.get(1, 9).has(Module::ON));
+    }
+
+    #[test]
+    fn rmqr_works() {
+        let qr_code = generate("hello", &QrOptions::new().symbol(Some(Symbol::Rmqr))).unwrap();
+        assert_eq!(qr_code.symbol, Symbol::Rmqr);
+        assert_eq!(qr_code.version, Version(1));
+        assert_eq!(qr_code.ecl, ECL::Medium);
+        assert_eq!((qr_code.matrix.width, qr_code.matrix.height), (43, 7));
+
+        let matrix = &qr_code.matrix;
+        // finder and sub finder centers
+        assert!(matrix.get(3, 3).has(Module::FINDER_CENTER | Module::ON));
+        assert!(matrix.get(40, 4).has(Module::FINDER_CENTER | Module::ON));
+        // alignment center
+        assert!(matrix.get(21, 1).has(Module::ALIGNMENT_CENTER));
+        assert!(!matrix.get(21, 1).has(Module::ON));
+
+        for version in 1..=32 {
+            let qr_code = generate(
+                "1",
+                &QrOptions::new()
+                    .symbol(Some(Symbol::Rmqr))
+                    .min_version(Version(version))
+                    .strict_version(true),
+            )
+            .unwrap();
+            let (width, height) = RMQR_SIZES[version];
+            assert_eq!(
+                (qr_code.matrix.width, qr_code.matrix.height),
+                (width, height)
+            );
+
+            let data_modules = qr_code
+                .matrix
+                .value
+                .iter()
+                .filter(|module| module.has(Module::DATA))
+                .count();
+            assert_eq!(data_modules, RMQR_DATA_MODULES[version] as usize);
+        }
+
+        let result = generate(
+            "hello",
+            &QrOptions::new()
+                .symbol(Some(Symbol::Rmqr))
+                .eci(Some(Eci::UTF8)),
+        );
+        assert_eq!(result.unwrap_err(), QrError::InvalidEncoding);
+    }
+}
@@ -1,96 +1,366 @@
-use crate::matrix::{Matrix, Module};
+use crate::matrix::{BitMatrix, Matrix, Module};
 
-// todo UNTESTED CODE: HERE BE DRAGONS
-// if score is wrong for all masks, then this still works
-pub fn score(matrix: &Matrix<Module>) -> u32 {
-    // todo what are perf implications of scoring all masks
-    // 8 masks * 5 iterations (blocks + rows are non sequential access)
-
-    fn dark_proportion(matrix: &Matrix<Module>) -> u32 {
-        let mut dark = 0;
-        for y in 0..matrix.width {
-            for x in 0..matrix.width {
-                if matrix.get(x, y).has(Module::DATA) {
-                    dark += 1;
-                }
-            }
-        }
+/// Picks the mask when none is given, by the lowest cost of each masked candidate.
+///
+/// Candidates have data, format info, and function patterns placed.
+/// Closures `Fn(&Matrix<Module>) -> u32` are also selectors.
+pub trait MaskSelector {
+    fn cost(&self, matrix: &Matrix<Module>) -> u32;
+    /// Same as `cost`, but from the dark modules as rows and as columns (`rows.transpose()`).
+    ///
+    /// Only called for QR. If this returns Some, no `Matrix<Module>` is built per candidate.
+    fn cost_bits(&self, _rows: &BitMatrix, _cols: &BitMatrix) -> Option<u32> {
+        None
+    }
+}
 
-        let percent = (dark * 20) / (20 * (matrix.width as u32) * (matrix.width as u32));
-        let middle = 50;
-        let diff = if percent < middle {
-            middle - percent
+impl<F: Fn(&Matrix<Module>) -> u32> MaskSelector for F {
+    fn cost(&self, matrix: &Matrix<Module>) -> u32 {
+        self(matrix)
+    }
+}
+
+impl std::fmt::Debug for dyn MaskSelector {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("MaskSelector")
+    }
+}
+
+/// The default selector, `score` for QR and `score_micro` for Micro QR
+#[derive(Clone, Copy, Debug, Default)]
+pub struct IsoPenalty;
+
+impl MaskSelector for IsoPenalty {
+    fn cost(&self, matrix: &Matrix<Module>) -> u32 {
+        // Micro QR is at most 17x17, QR is at least 21x21
+        if matrix.width < 21 {
+            u32::MAX - score_micro(matrix)
         } else {
-            percent - middle
+            score(matrix)
+        }
+    }
+    fn cost_bits(&self, rows: &BitMatrix, cols: &BitMatrix) -> Option<u32> {
+        Some(score_bits(rows, cols))
+    }
+}
+
+/// ISO 18004 penalty score of the whole symbol, lowest score is best.
+///
+/// Format info must already be placed, because it is part of the score.
+pub fn score(matrix: &Matrix<Module>) -> u32 {
+    let rows = BitMatrix::from_matrix(matrix, Module::ON);
+    score_bits(&rows, &rows.transpose())
+}
+
+/// Same as `score`, but from the dark modules as rows and as columns (`rows.transpose()`)
+pub fn score_bits(rows: &BitMatrix, cols: &BitMatrix) -> u32 {
+    let row_penalty = (0..rows.height).map(|y| line_penalty(rows.row(y), rows.width));
+    let col_penalty = (0..cols.height).map(|x| line_penalty(cols.row(x), cols.width));
+
+    row_penalty.chain(col_penalty).sum::<u32>() + block_penalty(rows) + balance_penalty(rows)
+}
+
+// Enough for 177 modules with 4 light modules on each side
+const LINE_WORDS: usize = 4;
+type Line = [u64; LINE_WORDS];
+
+/// N1 (3 + i for runs of 5 + i) and N3 (40 per finder-like pattern) for a row or column
+fn line_penalty(words: &[u64], len: usize) -> u32 {
+    let mut line = [0; LINE_WORDS];
+    line[..words.len()].copy_from_slice(words);
+
+    // bit i is set if modules i and i + 1 are the same color
+    let same = and(not(xor(line, shr(line, 1))), ones(len - 1));
+    // every 5 in a row adds 1, and the start of each run adds the other 2
+    let run5 = and(and(same, shr(same, 1)), and(shr(same, 2), shr(same, 3)));
+    let run_start = and(run5, not(shl(same, 1)));
+    let mut score = count(run5) + 2 * count(run_start);
+
+    // dark light dark dark dark light dark, with 4 light on either side
+    // outside the symbol is the quiet zone, which is light, so pad with 4 light
+    let padded = shl(line, 4);
+    let mut pattern = [u64::MAX; LINE_WORDS];
+    for (i, dark) in [true, false, true, true, true, false, true]
+        .into_iter()
+        .enumerate()
+    {
+        let module = shr(padded, i);
+        pattern = and(pattern, if dark { module } else { not(module) });
+    }
+    let mut before = [0; LINE_WORDS];
+    let mut after = [0; LINE_WORDS];
+    for i in 0..4 {
+        before = or(before, shl(padded, i + 1));
+        after = or(after, shr(padded, i + 7));
+    }
+    score += 40 * count(and(pattern, not(and(before, after))));
+
+    score
+}
+
+/// N2, 3 for each 2x2 block of the same color, overlapping blocks count
+fn block_penalty(rows: &BitMatrix) -> u32 {
+    let mut score = 0;
+    let mut top = [0; LINE_WORDS];
+    top[..rows.row(0).len()].copy_from_slice(rows.row(0));
+    for y in 1..rows.height {
+        let mut bottom = [0; LINE_WORDS];
+        bottom[..rows.row(y).len()].copy_from_slice(rows.row(y));
+
+        // bit i is set if modules i and i + 1 are the same color
+        let same_top = not(xor(top, shr(top, 1)));
+        let same_bottom = not(xor(bottom, shr(bottom, 1)));
+        let same_col = not(xor(top, bottom));
+        let block = and(and(same_top, same_bottom), same_col);
+        score += 3 * count(and(block, ones(rows.width - 1)));
+
+        top = bottom;
+    }
+    score
+}
+
+/// N4, 10 for each full 5% the proportion of dark modules is away from 50%
+fn balance_penalty(rows: &BitMatrix) -> u32 {
+    let total = rows.width * rows.height;
+    let dark = rows.count_ones();
+
+    let variance = (dark * 2).abs_diff(total) * 10 / total;
+    variance as u32 * 10
+}
+
+fn zip(a: Line, b: Line, f: impl Fn(u64, u64) -> u64) -> Line {
+    std::array::from_fn(|i| f(a[i], b[i]))
+}
+fn and(a: Line, b: Line) -> Line {
+    zip(a, b, |a, b| a & b)
+}
+fn or(a: Line, b: Line) -> Line {
+    zip(a, b, |a, b| a | b)
+}
+fn xor(a: Line, b: Line) -> Line {
+    zip(a, b, |a, b| a ^ b)
+}
+fn not(a: Line) -> Line {
+    a.map(|a| !a)
+}
+/// Bit i becomes bit i - n
+fn shr(a: Line, n: usize) -> Line {
+    std::array::from_fn(|i| {
+        let carry = match a.get(i + 1) {
+            Some(&next) if n > 0 => next << (64 - n),
+            _ => 0,
         };
-        let k = (diff) / 5;
-        10 * k
-    }
-
-    fn blocks(matrix: &Matrix<Module>) -> u32 {
-        let mut score = 0;
-        for y in 0..matrix.width - 1 {
-            for x in 0..matrix.width - 1 {
-                let curr = matrix.get(x, y).has(Module::ON);
-                let tr = matrix.get(x + 1, y).has(Module::ON);
-                let bl = matrix.get(x, y + 1).has(Module::ON);
-                let br = matrix.get(x + 1, y + 1).has(Module::ON);
-                if curr == tr && curr == bl && curr == br {
-                    score += 3;
-                }
+        (a[i] >> n) | carry
+    })
+}
+/// Bit i becomes bit i + n
+fn shl(a: Line, n: usize) -> Line {
+    std::array::from_fn(|i| {
+        let carry = match i.checked_sub(1) {
+            Some(prev) if n > 0 => a[prev] >> (64 - n),
+            _ => 0,
+        };
+        (a[i] << n) | carry
+    })
+}
+/// The first `len` bits
+fn ones(len: usize) -> Line {
+    std::array::from_fn(|i| match len.saturating_sub(i * 64) {
+        0 => 0,
+        n if n >= 64 => u64::MAX,
+        n => (1 << n) - 1,
+    })
+}
+fn count(a: Line) -> u32 {
+    a.iter().map(|word| word.count_ones()).sum()
+}
+
+/// Micro QR only scores dark modules on the right and bottom edges, highest score is best
+pub fn score_micro(matrix: &Matrix<Module>) -> u32 {
+    let last = matrix.width - 1;
+    let mut right = 0;
+    let mut bottom = 0;
+    // skip timing patterns
+    for i in 1..matrix.width {
+        right += matrix.get(last, i).has(Module::ON) as u32;
+        bottom += matrix.get(i, last).has(Module::ON) as u32;
+    }
+
+    if right <= bottom {
+        right * 16 + bottom
+    } else {
+        bottom * 16 + right
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        generate,
+        qr_code::{Mask, Mode, QrCode, Version, ECL},
+        QrOptions,
+    };
+
+    #[test]
+    fn line_penalty_works() {
+        let line = |s: &str| {
+            let mut words = [0; 3];
+            for (i, c) in s.chars().enumerate() {
+                words[i / 64] |= ((c == '#') as u64) << (i % 64);
+            }
+            line_penalty(&words, s.len())
+        };
+
+        assert_eq!(line("#.#.#.#.#"), 0);
+        assert_eq!(line("#####.#.#"), 3);
+        assert_eq!(line("........."), 7);
+        // finder-like pattern at the edge, outside is light
+        assert_eq!(line("#.###.#.#"), 40);
+        assert_eq!(line("#.#.###.#.#"), 0);
+        assert_eq!(line("#.#.#.###.#.#.#"), 0);
+        assert_eq!(line("#.....#.###.#"), 3 + 40);
+        // runs and patterns across words
+        let long = format!("{}{}", ".".repeat(60), "#.###.#....#######");
+        assert_eq!(line(&long), 3 + 55 + 40 + 5);
+    }
+
+    #[test]
+    fn score_bits_matches_matrix() {
+        // closures only score whole matrices, so this compares against the packed path
+        let by_matrix = |matrix: &Matrix<Module>| score(matrix);
+        for version in [1, 2, 7, 14, 27, 40] {
+            for ecl in [ECL::Low, ECL::High] {
+                let options = || QrOptions::new().min_version(Version(version)).min_ecl(ecl);
+                let expected = generate("fuqr", &options().mask_selector(by_matrix)).unwrap();
+                let qr_code = generate("fuqr", &options()).unwrap();
+                assert_eq!(qr_code.mask, expected.mask);
+                assert_eq!(qr_code.matrix.value, expected.matrix.value);
             }
         }
-        score
     }
 
-    // detects streaks >= 5 and finder patterns
-    fn line_patterns(matrix: &Matrix<Module>, col: bool) -> u32 {
-        let mut score = 0;
-        let (y_mult, x_mult) = match col {
-            true => (matrix.width, 1),
-            false => (1, matrix.width),
-        };
+    const INPUTS: [&str; 5] = [
+        "hello world",
+        "zhengkyl/fuqr",
+        "https://github.com/zhengkyl/fuqr",
+        "feeling unemployed qr code generator",
+        "mask penalty mask penalty mask penalty",
+    ];
+
+    const VERSIONS: [(usize, fast_qr::Version); 8] = [
+        (1, fast_qr::Version::V01),
+        (2, fast_qr::Version::V02),
+        (3, fast_qr::Version::V03),
+        (5, fast_qr::Version::V05),
+        (7, fast_qr::Version::V07),
+        (10, fast_qr::Version::V10),
+        (20, fast_qr::Version::V20),
+        (40, fast_qr::Version::V40),
+    ];
+
+    const ECLS: [(ECL, qrcode::EcLevel, fast_qr::ECL); 4] = [
+        (ECL::Low, qrcode::EcLevel::L, fast_qr::ECL::L),
+        (ECL::Medium, qrcode::EcLevel::M, fast_qr::ECL::M),
+        (ECL::Quartile, qrcode::EcLevel::Q, fast_qr::ECL::Q),
+        (ECL::High, qrcode::EcLevel::H, fast_qr::ECL::H),
+    ];
 
-        let pattern_1 = 0b0000_1011101;
-        let pattern_2 = 0b1011101_0000;
+    const MASKS: [(Mask, fast_qr::Mask); 8] = [
+        (Mask::M0, fast_qr::Mask::Checkerboard),
+        (Mask::M1, fast_qr::Mask::HorizontalLines),
+        (Mask::M2, fast_qr::Mask::VerticalLines),
+        (Mask::M3, fast_qr::Mask::DiagonalLines),
+        (Mask::M4, fast_qr::Mask::LargeCheckerboard),
+        (Mask::M5, fast_qr::Mask::Fields),
+        (Mask::M6, fast_qr::Mask::Diamonds),
+        (Mask::M7, fast_qr::Mask::Meadow),
+    ];
 
-        for y in 0..matrix.width {
-            let mut streak = 1;
-            let mut streak_v = matrix.value[y * y_mult + 0].has(Module::ON);
+    fn strict_qr(input: &str, version: usize, ecl: ECL, mask: Option<Mask>) -> Option<QrCode> {
+        let options = QrOptions::new()
+            .mode(Some(Mode::Byte))
+            .min_version(Version(version))
+            .strict_version(true)
+            .min_ecl(ecl)
+            .strict_ecl(true)
+            .mask(mask);
+        generate(input, &options).ok()
+    }
+
+    fn same_modules(qr_code: &QrCode, mut dark: impl FnMut(usize, usize) -> bool) -> bool {
+        let width = qr_code.matrix.width;
+        (0..width * width)
+            .all(|i| dark(i / width, i % width) == qr_code.matrix.value[i].has(Module::ON))
+    }
 
-            let mut window: u16 = streak_v as u16;
+    #[test]
+    fn score_matches_qrcode() {
+        // qrcode doesn't round the N4 dark proportion to 5% steps, so a dark proportion
+        // near a step can pick another mask, which must then score no better for us
+        let mut differs = 0;
+        for input in INPUTS {
+            for (version, _) in VERSIONS {
+                for (ecl, qrcode_ecl, _) in ECLS {
+                    let Some(qr_code) = strict_qr(input, version, ecl, None) else {
+                        continue;
+                    };
 
-            for x in 1..matrix.width {
-                let curr = matrix.value[y * y_mult + x * x_mult].has(Module::ON);
-                if curr == streak_v {
-                    streak += 1;
-                    if streak == 5 {
-                        score += 3;
-                    } else if streak > 5 {
-                        score += 1;
+                    let mut bits = qrcode::bits::Bits::new(qrcode::Version::Normal(version as i16));
+                    bits.push_byte_data(input.as_bytes()).unwrap();
+                    bits.push_terminator(qrcode_ecl).unwrap();
+                    let colors = qrcode::QrCode::with_bits(bits, qrcode_ecl)
+                        .unwrap()
+                        .to_colors();
+                    let width = qr_code.matrix.width;
+                    let dark = |y: usize, x: usize| colors[y * width + x] == qrcode::Color::Dark;
+                    if same_modules(&qr_code, dark) {
+                        continue;
                     }
-                } else {
-                    streak = 1;
-                    streak_v = curr;
+
+                    differs += 1;
+                    let other = MASKS
+                        .iter()
+                        .filter_map(|&(mask, _)| strict_qr(input, version, ecl, Some(mask)))
+                        .find(|other| same_modules(other, dark))
+                        .unwrap_or_else(|| panic!("{input} v{version} {ecl:?} matches no mask"));
+                    assert!(
+                        score(&qr_code.matrix) <= score(&other.matrix),
+                        "{input} v{version} {ecl:?} chose {:?} over {:?}",
+                        qr_code.mask,
+                        other.mask
+                    );
                 }
+            }
+        }
+        assert!(differs < 8, "{differs} masks differ from qrcode");
+    }
 
-                window <<= 1;
-                window |= curr as u16;
-                // 10 = pattern.len() - 1
-                if x >= 10 {
-                    window &= 0b111_1111_1111;
-                    if window == pattern_1 || window == pattern_2 {
-                        score += 40;
+    #[test]
+    fn masks_match_fast_qr() {
+        // fast_qr's mask penalty isn't the ISO 18004 one, so its automatic choice often
+        // differs, only forced masks are compared
+        for input in INPUTS {
+            for (version, fast_version) in VERSIONS {
+                for (ecl, _, fast_ecl) in ECLS {
+                    for (mask, fast_mask) in MASKS {
+                        let Some(qr_code) = strict_qr(input, version, ecl, Some(mask)) else {
+                            continue;
+                        };
+                        let expected = fast_qr::QRBuilder::new(input)
+                            .mode(fast_qr::Mode::Byte)
+                            .version(fast_version)
+                            .ecl(fast_ecl)
+                            .mask(fast_mask)
+                            .build()
+                            .unwrap();
+                        assert!(
+                            same_modules(&qr_code, |y, x| expected[y][x].value()),
+                            "{input} v{version} {ecl:?} {mask:?}"
+                        );
                     }
                 }
             }
         }
-
-        score
     }
-
-    dark_proportion(matrix)
-        + blocks(matrix)
-        + line_patterns(matrix, true)
-        + line_patterns(matrix, false)
 }
@@ -1,22 +1,30 @@
 use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, BitXor, BitXorAssign};
 
 use crate::{
-    constants::{FORMAT_INFO, VERSION_INFO},
+    constants::{
+        FORMAT_INFO, MICRO_FORMAT_INFO, MICRO_SYMBOL_NUMBER, RMQR_FORMAT_INFO, VERSION_INFO,
+    },
     qr_code::{Mask, Version, ECL},
 };
 
-#[derive(Debug)]
-pub struct Matrix<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> {
+#[derive(Clone, Debug)]
+pub struct Matrix<T: Copy> {
     pub value: Vec<T>,
     pub width: usize,
+    /// Same as width, except for rMQR
+    pub height: usize,
 }
 
-impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
+impl<T: Copy> Matrix<T> {
     pub fn new(version: Version, init: T) -> Self {
         let width = version.0 * 4 + 17;
+        Self::with_size(width, width, init)
+    }
+    pub fn with_size(width: usize, height: usize, init: T) -> Self {
         Matrix {
-            value: vec![init; (width) * (width)],
+            value: vec![init; (width) * (height)],
             width,
+            height,
         }
     }
     pub fn get(&self, x: usize, y: usize) -> T {
@@ -28,42 +36,48 @@ impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
     pub fn set(&mut self, x: usize, y: usize, value: T) {
         self.value[y * self.width + x] = value;
     }
+}
 
+impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
     pub fn set_finder(&mut self) {
-        for (x, mut y) in [(0, 0), (0, self.width - 7), (self.width - 7, 0)] {
-            for i in 0..7 {
-                self.set(x + i, y, (Module::FINDER | Module::ON).into());
-            }
-            y += 1;
+        for (x, y) in [(0, 0), (0, self.width - 7), (self.width - 7, 0)] {
+            self.set_finder_at(x, y);
+        }
+    }
 
-            self.set(x + 0, y, (Module::FINDER | Module::ON).into());
-            for i in 1..6 {
-                self.set(x + i, y, Module::FINDER.into());
-            }
-            self.set(x + 6, y, (Module::FINDER | Module::ON).into());
-            y += 1;
+    fn set_finder_at(&mut self, x: usize, mut y: usize) {
+        for i in 0..7 {
+            self.set(x + i, y, (Module::FINDER | Module::ON).into());
+        }
+        y += 1;
 
-            for _ in 0..3 {
-                self.set(x + 0, y, (Module::FINDER | Module::ON).into());
-                self.set(x + 1, y, (Module::FINDER).into());
-                self.set(x + 2, y, (Module::FINDER_CENTER | Module::ON).into());
-                self.set(x + 3, y, (Module::FINDER_CENTER | Module::ON).into());
-                self.set(x + 4, y, (Module::FINDER_CENTER | Module::ON).into());
-                self.set(x + 5, y, (Module::FINDER).into());
-                self.set(x + 6, y, (Module::FINDER | Module::ON).into());
-                y += 1;
-            }
+        self.set(x + 0, y, (Module::FINDER | Module::ON).into());
+        for i in 1..6 {
+            self.set(x + i, y, Module::FINDER.into());
+        }
+        self.set(x + 6, y, (Module::FINDER | Module::ON).into());
+        y += 1;
 
+        for _ in 0..3 {
             self.set(x + 0, y, (Module::FINDER | Module::ON).into());
-            for i in 1..6 {
-                self.set(x + i, y, (Module::FINDER).into());
-            }
+            self.set(x + 1, y, (Module::FINDER).into());
+            self.set(x + 2, y, (Module::FINDER_CENTER | Module::ON).into());
+            self.set(x + 3, y, (Module::FINDER_CENTER | Module::ON).into());
+            self.set(x + 4, y, (Module::FINDER_CENTER | Module::ON).into());
+            self.set(x + 5, y, (Module::FINDER).into());
             self.set(x + 6, y, (Module::FINDER | Module::ON).into());
             y += 1;
+        }
 
-            for i in 0..7 {
-                self.set(x + i, y, (Module::FINDER | Module::ON).into());
-            }
+        self.set(x + 0, y, (Module::FINDER | Module::ON).into());
+        for i in 1..6 {
+            self.set(x + i, y, (Module::FINDER).into());
+        }
+        self.set(x + 6, y, (Module::FINDER | Module::ON).into());
+        y += 1;
+
+        for i in 0..7 {
+            self.set(x + i, y, (Module::FINDER | Module::ON).into());
         }
     }
 
@@ -138,27 +152,9 @@ impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
         for i in 0..15 {
             let on = ((format_info >> i) as u8 & 1).into();
 
-            let y = match i {
-                i if i < 6 => i,
-                6 => 7,
-                _ => 8,
-            };
-            let x = match i {
-                i if i < 8 => 8,
-                8 => 7,
-                _ => 14 - i,
-            };
+            let [(x, y), (copy_x, copy_y)] = format_positions(self.width, i);
             self.set(x, y, (Module::FORMAT | on).into());
-
-            let y = match i {
-                i if i < 8 => 8,
-                _ => self.width - (15 - i),
-            };
-            let x = match i {
-                i if i < 8 => self.width - (i + 1),
-                _ => 8,
-            };
-            self.set(x, y, (Module::FORMAT_COPY | on).into());
+            self.set(copy_x, copy_y, (Module::FORMAT_COPY | on).into());
         }
 
         // always set bit, not part of format info
@@ -175,16 +171,22 @@ impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
         for i in 0..18 {
             let on = ((info >> i) as u8 & 1).into();
 
-            let x = i / 3;
-            let y = i % 3;
-
-            self.set(x, y + self.width - 11, (Module::VERSION | on).into());
-            self.set(y + self.width - 11, x, (Module::VERSION_COPY | on).into());
+            let [(x, y), (copy_x, copy_y)] = version_positions(self.width, i);
+            self.set(x, y, (Module::VERSION | on).into());
+            self.set(copy_x, copy_y, (Module::VERSION_COPY | on).into());
         }
     }
 
     /// This must run AFTER alignment, timing, version placed
     pub fn set_data(&mut self, mut get_value: impl FnMut() -> T) {
+        for (x, y) in self.data_positions() {
+            self.set(x, y, get_value());
+        }
+    }
+
+    /// Empty modules in data placement order, see `set_data`
+    pub fn data_positions(&self) -> Vec<(usize, usize)> {
+        let mut positions = Vec::new();
         let mut col = self.width - 1;
         let mut row = self.width - 1;
 
@@ -196,10 +198,10 @@ impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
         loop {
             loop {
                 if self.get(col, row).into() == Module(0) {
-                    self.set(col, row, get_value());
+                    positions.push((col, row));
                 }
                 if self.get(col - 1, row).into() == Module(0) {
-                    self.set(col - 1, row, get_value());
+                    positions.push((col - 1, row));
                 }
                 if row == row_end {
                     break;
@@ -208,7 +210,7 @@ impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
             }
 
             if col == 1 {
-                break;
+                return positions;
             }
 
             col -= 2;
@@ -233,6 +235,250 @@ impl<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>> Matrix<T> {
             }
         }
     }
+
+    /// Micro QR has 1 finder, and the separator is marked as FINDER so data skips it
+    pub fn set_micro_finder(&mut self) {
+        self.set_finder_at(0, 0);
+        for i in 0..8 {
+            self.set(7, i, Module::FINDER.into());
+            self.set(i, 7, Module::FINDER.into());
+        }
+    }
+
+    pub fn set_micro_timing(&mut self) {
+        for i in 8..self.width {
+            let module = Module::TIMING | ((i as u8 & 1) ^ 1).into();
+            self.set(i, 0, module.into());
+            self.set(0, i, module.into());
+        }
+    }
+
+    /// Version is 1 - 4 for M1 - M4
+    pub fn set_micro_format(&mut self, version: Version, ecl: ECL, mask: Mask) {
+        let symbol_number = MICRO_SYMBOL_NUMBER[version.0][ecl as usize] as usize;
+        let format_info = MICRO_FORMAT_INFO[symbol_number][mask as usize];
+        for i in 0..15 {
+            let on = ((format_info >> i) as u8 & 1).into();
+
+            let (x, y) = match i {
+                i if i < 8 => (8, i + 1),
+                _ => (15 - i, 8),
+            };
+            self.set(x, y, (Module::FORMAT | on).into());
+        }
+    }
+
+    /// This must run AFTER finder, timing, format placed
+    pub fn set_micro_data(&mut self, mut get_value: impl FnMut() -> T) {
+        // no vertical timing belt to skip, column 0 is timing
+        let mut upwards = true;
+        for col in (2..self.width).rev().step_by(2) {
+            for i in 0..self.width {
+                let row = if upwards { self.width - 1 - i } else { i };
+                if self.get(col, row).into() == Module(0) {
+                    self.set(col, row, get_value());
+                }
+                if self.get(col - 1, row).into() == Module(0) {
+                    self.set(col - 1, row, get_value());
+                }
+            }
+            upwards = !upwards;
+        }
+    }
+    /// rMQR has a finder on the left, a smaller finder on the bottom right,
+    /// and corner patterns on the other 2 corners. Separators are marked as FINDER.
+    pub fn set_rmqr_finder(&mut self) {
+        let (w, h) = (self.width, self.height);
+
+        self.set_finder_at(0, 0);
+        for i in 0..8.min(h) {
+            self.set(7, i, Module::FINDER.into());
+        }
+        if h >= 9 {
+            for i in 0..8 {
+                self.set(i, 7, Module::FINDER.into());
+            }
+        }
+
+        for y in h - 5..h {
+            for x in w - 5..w {
+                let ring = (x == w - 5 || x == w - 1 || y == h - 5 || y == h - 1) as u8;
+                self.set(x, y, (Module::FINDER | ring.into()).into());
+            }
+        }
+        self.set(w - 3, h - 3, (Module::FINDER_CENTER | Module::ON).into());
+
+        // top right
+        self.set(w - 1, 0, (Module::FINDER | Module::ON).into());
+        self.set(w - 2, 0, (Module::FINDER | Module::ON).into());
+        self.set(w - 1, 1, (Module::FINDER | Module::ON).into());
+        self.set(w - 2, 1, Module::FINDER.into());
+
+        // bottom left
+        for i in 0..3 {
+            self.set(i, h - 1, (Module::FINDER | Module::ON).into());
+        }
+        if h >= 11 {
+            self.set(0, h - 2, (Module::FINDER | Module::ON).into());
+            self.set(1, h - 2, Module::FINDER.into());
+        }
+    }
+
+    /// 3x3 rings on the top and bottom edges, with light centers
+    pub fn set_rmqr_alignment(&mut self) {
+        for &col in rmqr_align_cols(self.width) {
+            for (row, center) in [(1, 1), (self.height - 2, self.height - 2)] {
+                for y in row - 1..=row + 1 {
+                    for x in col - 1..=col + 1 {
+                        let module = if x == col && y == center {
+                            Module::ALIGNMENT_CENTER
+                        } else {
+                            Module::ALIGNMENT | Module::ON
+                        };
+                        self.set(x, y, module.into());
+                    }
+                }
+            }
+        }
+    }
+
+    /// This must run AFTER finder and alignment placed, b/c timing fills the remaining edges
+    /// and columns between alignment patterns
+    pub fn set_rmqr_timing(&mut self) {
+        for x in 0..self.width {
+            let module = Module::TIMING | ((x as u8 & 1) ^ 1).into();
+            for y in [0, self.height - 1] {
+                if self.get(x, y).into() == Module(0) {
+                    self.set(x, y, module.into());
+                }
+            }
+        }
+
+        let mut cols = vec![0, self.width - 1];
+        cols.extend_from_slice(rmqr_align_cols(self.width));
+        for y in 0..self.height {
+            let module = Module::TIMING | ((y as u8 & 1) ^ 1).into();
+            for &x in cols.iter() {
+                if self.get(x, y).into() == Module(0) {
+                    self.set(x, y, module.into());
+                }
+            }
+        }
+    }
+
+    /// Version is 1 - 32, in the same order as `RMQR_SIZES`
+    pub fn set_rmqr_format(&mut self, version: Version, ecl: ECL) {
+        let ecl_bit = (ecl > ECL::Medium) as usize;
+        let format_info = RMQR_FORMAT_INFO[(ecl_bit << 5) | (version.0 - 1)];
+        // each copy is masked differently
+        let finder_info = format_info ^ 0b011111_101010_110010;
+        let sub_finder_info = format_info ^ 0b100000_101001_111011;
+
+        for i in 0..18 {
+            let on = ((finder_info >> i) as u8 & 1).into();
+            self.set(8 + i / 5, 1 + i % 5, (Module::FORMAT | on).into());
+
+            let on = ((sub_finder_info >> i) as u8 & 1).into();
+            let (x, y) = match i {
+                i if i < 15 => (self.width - 8 + i / 5, self.height - 6 + i % 5),
+                _ => (self.width - 20 + i, self.height - 6),
+            };
+            self.set(x, y, (Module::FORMAT_COPY | on).into());
+        }
+    }
+
+    /// This must run AFTER finder, alignment, timing, format placed
+    pub fn set_rmqr_data(&mut self, mut get_value: impl FnMut() -> T) {
+        // right edge is timing, so pairs end with column 0
+        let mut upwards = true;
+        for col in (1..self.width - 1).rev().step_by(2) {
+            for i in 0..self.height {
+                let row = if upwards { self.height - 1 - i } else { i };
+                if self.get(col, row).into() == Module(0) {
+                    self.set(col, row, get_value());
+                }
+                if self.get(col - 1, row).into() == Module(0) {
+                    self.set(col - 1, row, get_value());
+                }
+            }
+            upwards = !upwards;
+        }
+    }
+}
+
+/// One bit per module, packed into u64 words per row, least significant bit first.
+///
+/// Bits past the end of a row are always 0.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct BitMatrix {
+    words: Vec<u64>,
+    stride: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+impl BitMatrix {
+    pub fn new(width: usize, height: usize) -> Self {
+        let stride = width.div_ceil(64);
+        BitMatrix {
+            words: vec![0; stride * height],
+            stride,
+            width,
+            height,
+        }
+    }
+    /// Sets the bits of modules that have all of `flags`
+    pub fn from_matrix<T: Copy + From<Module> + Into<Module> + BitOrAssign<Module>>(
+        matrix: &Matrix<T>,
+        flags: Module,
+    ) -> Self {
+        let mut bits = BitMatrix::new(matrix.width, matrix.height);
+        for y in 0..matrix.height {
+            let row = bits.row_mut(y);
+            for x in 0..matrix.width {
+                let on = matrix.get(x, y).into().has(flags) as u64;
+                row[x / 64] |= on << (x % 64);
+            }
+        }
+        bits
+    }
+    pub fn get(&self, x: usize, y: usize) -> bool {
+        (self.words[y * self.stride + x / 64] >> (x % 64)) & 1 == 1
+    }
+    pub fn set(&mut self, x: usize, y: usize, on: bool) {
+        let word = &mut self.words[y * self.stride + x / 64];
+        let bit = 1 << (x % 64);
+        if on {
+            *word |= bit;
+        } else {
+            *word &= !bit;
+        }
+    }
+    pub fn row(&self, y: usize) -> &[u64] {
+        &self.words[y * self.stride..(y + 1) * self.stride]
+    }
+    /// Bits past the end of the row must be left as 0
+    pub fn row_mut(&mut self, y: usize) -> &mut [u64] {
+        &mut self.words[y * self.stride..(y + 1) * self.stride]
+    }
+    pub fn count_ones(&self) -> usize {
+        self.words
+            .iter()
+            .map(|word| word.count_ones() as usize)
+            .sum()
+    }
+    /// Rows become columns, so column lines can be read as whole words
+    pub fn transpose(&self) -> Self {
+        let mut bits = BitMatrix::new(self.height, self.width);
+        for y in 0..self.height {
+            for x in 0..self.width {
+                if self.get(x, y) {
+                    bits.set(y, x, true);
+                }
+            }
+        }
+        bits
+    }
 }
 
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
@@ -324,3 +570,47 @@ const ALIGN_OFFSETS: [usize; 34] = [
     24, 24, 26, 26, 26, 28, 28, // 28-34
     24, 26, 26, 26, 28, 28, // 35-40
 ];
+
+/// Centers of rMQR alignment patterns, also columns with vertical timing
+fn rmqr_align_cols(width: usize) -> &'static [usize] {
+    match width {
+        27 => &[],
+        43 => &[21],
+        59 => &[19, 39],
+        77 => &[25, 51],
+        99 => &[23, 49, 75],
+        139 => &[27, 55, 83, 111],
+        _ => unreachable!("Invalid rMQR width"),
+    }
+}
+
+/// Position of format info bit `i`, followed by its copy
+pub(crate) fn format_positions(width: usize, i: usize) -> [(usize, usize); 2] {
+    let y = match i {
+        i if i < 6 => i,
+        6 => 7,
+        _ => 8,
+    };
+    let x = match i {
+        i if i < 8 => 8,
+        8 => 7,
+        _ => 14 - i,
+    };
+
+    let copy_y = match i {
+        i if i < 8 => 8,
+        _ => width - (15 - i),
+    };
+    let copy_x = match i {
+        i if i < 8 => width - (i + 1),
+        _ => 8,
+    };
+    [(x, y), (copy_x, copy_y)]
+}
+
+/// Position of version info bit `i`, followed by its copy
+pub(crate) fn version_positions(width: usize, i: usize) -> [(usize, usize); 2] {
+    let x = i / 3;
+    let y = i % 3;
+    [(x, y + width - 11), (y + width - 11, x)]
+}
@@ -1,10 +1,13 @@
+use std::cmp::Reverse;
+
 use crate::{
     bit_info::{BitInfo, Info},
-    constants::{GEN_POLYNOMIALS, NUM_BLOCKS, NUM_DATA_MODULES, NUM_EC_CODEWORDS},
+    constants::{NUM_BLOCKS, NUM_DATA_MODULES, NUM_EC_CODEWORDS},
     data::{BitVec, Data},
-    error_correction::remainder,
+    error_correction::Encoder,
     matrix::{Matrix, Module},
-    qr_code::{mask_fn, Mask, QrCode},
+    qr_code::{mask_fn, Mask, QrCode, Symbol, Version, ECL},
+    segment::Segment,
 };
 
 #[derive(Debug, Clone, Copy)]
@@ -30,6 +33,70 @@ pub struct Qart {
     pub bit_info: BitInfo,
     pub blocks: Vec<BitVec>,
     pub block_weights: Vec<Vec<WeightPixel>>,
+    pub segments: Vec<Segment>,
+    error_budget: u8,
+    fill: Fill,
+}
+
+/// What modules without any pixel weight should look like.
+///
+/// Fill only uses bits that pixel weights don't need, so it never makes the image worse.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fill {
+    /// Standard padding codewords, which repeat and show up as mask patterns
+    Padding,
+    /// White noise, from a hash of the seed and module position
+    Noise(u64),
+    /// Interleaved gradient noise, more even than `Noise` with fewer clumps
+    BlueNoise(u64),
+    /// Weighted majority of pixels up to 2 modules away, or `Noise` if there is none
+    Neighbours(u64),
+}
+
+impl Fill {
+    /// Module color, None to leave the module to the padding
+    fn color(
+        &self,
+        x: usize,
+        y: usize,
+        pixel_weights: &[WeightPixel],
+        width: usize,
+    ) -> Option<bool> {
+        let noise = |seed: u64| hash(seed, (y * width + x) as u64) & 1 == 1;
+        match *self {
+            Fill::Padding => None,
+            Fill::Noise(seed) => Some(noise(seed)),
+            Fill::BlueNoise(seed) => {
+                // offset by seed, so different seeds don't share a pattern
+                let offset = hash(seed, 0);
+                let x = x as f64 + (offset & 0xFFFF) as f64;
+                let y = y as f64 + (offset >> 48) as f64;
+                let ign = (52.982_918_9 * (0.067_110_56 * x + 0.005_837_15 * y).fract()).fract();
+                Some(ign < 0.5)
+            }
+            Fill::Neighbours(seed) => {
+                let near = |i: usize| i.saturating_sub(2)..(i + 3).min(width);
+                let mut votes = 0;
+                for ny in near(y) {
+                    for nx in near(x) {
+                        let pixel = pixel_weights[ny * width + nx];
+                        let weight = pixel.weight() as i32;
+                        votes += if pixel.value() { weight } else { -weight };
+                    }
+                }
+                Some(if votes == 0 { noise(seed) } else { votes > 0 })
+            }
+        }
+    }
+}
+
+/// splitmix64, so fill doesn't depend on the order modules are visited
+fn hash(seed: u64, i: u64) -> u64 {
+    let mut z = seed ^ i.wrapping_mul(0x9E37_79B9_7F4A_7C15);
+    z = z.wrapping_add(0x9E37_79B9_7F4A_7C15);
+    z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+    z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+    z ^ (z >> 31)
 }
 
 impl Qart {
@@ -59,8 +126,7 @@ impl Qart {
         data.bits.push_n(0, term_len);
         let orig_data_bit_len = data.bits.len();
         if data.bits.len() < num_data_codewords * 8 {
-            // TODO filling with 0 creates checkerboard
-            // perhaps fill with random noise
+            // standard padding, any bits without pixel weight are replaced by `Fill`
             data.bits.resize(num_data_codewords * 8, 0b11101100);
         }
 
@@ -78,10 +144,8 @@ impl Qart {
             let byte_start = data_i / 8;
             let mut data_codewords =
                 data.bits.as_ref()[byte_start..(byte_start + data_per_block)].to_vec();
-            let mut ecc = remainder(
-                &data_codewords,
-                &GEN_POLYNOMIALS[ecc_per_block][..ecc_per_block],
-            );
+            let mut ecc = vec![0; ecc_per_block];
+            Encoder::get(ecc_per_block).encode(&data_codewords, &mut ecc);
             data_codewords.append(&mut ecc);
             blocks.push(data_codewords.into());
 
@@ -100,10 +164,33 @@ impl Qart {
             bit_info: BitInfo::new(data.mode, data.version, data.ecl, mask),
             blocks,
             block_weights,
+            segments: std::mem::take(&mut data.segments),
+            error_budget: 0,
+            fill: Fill::Noise(0),
         }
     }
 
-    pub fn to_qr_code(mut self, pixel_weights: &[WeightPixel]) -> QrCode {
+    /// Percent (0 - 100) of each block's error correction capacity spent on matching pixels
+    /// that error correction modules can't, by deliberately writing wrong codewords.
+    ///
+    /// Wrong codewords make the code harder to scan, defaults to 0.
+    pub fn error_budget(mut self, percent: u8) -> Self {
+        self.error_budget = percent.min(100);
+        self
+    }
+
+    /// How to fill modules without pixel weight, defaults to `Fill::Noise(0)`
+    pub fn fill(mut self, fill: Fill) -> Self {
+        self.fill = fill;
+        self
+    }
+
+    pub fn to_qr_code(self, pixel_weights: &[WeightPixel]) -> QrCode {
+        self.to_qr_code_with_report(pixel_weights).0
+    }
+
+    /// Same as `to_qr_code`, along with how well the image and each error correction block matched
+    pub fn to_qr_code_with_report(mut self, pixel_weights: &[WeightPixel]) -> (QrCode, QartReport) {
         let width = self.bit_info.version.0 * 4 + 17;
         assert_eq!(pixel_weights.len(), width * width);
 
@@ -124,6 +211,20 @@ impl Qart {
 
         let mask = mask_fn(self.bit_info.mask);
 
+        // data bits are a prefix of each block, and must never be wrong
+        let fixed: Vec<usize> = self
+            .block_weights
+            .iter()
+            .map(|weights| weights.iter().take_while(|w| w.weight() == 127).count())
+            .collect();
+
+        // weight 0 bits to solve after all weighted bits, so they only use bits nothing else wants
+        let mut fill_bits: Vec<Vec<bool>> = self
+            .block_weights
+            .iter()
+            .map(|weights| vec![false; weights.len()])
+            .collect();
+
         for y in 0..width {
             for x in 0..width {
                 let bit = self.bit_info.matrix.get(x, y);
@@ -131,50 +232,81 @@ impl Qart {
                     continue;
                 }
 
-                // TODO reconsider randomizing "dead" pixels
-                // must be in image, and needs to not depend on order
                 if self.block_weights[bit.block as usize][bit.bit as usize].weight() < 127 {
-                    let value = mask(x as u16, y as u16) ^ pixel_weights[y * width + x].value();
+                    let pixel = pixel_weights[y * width + x];
+                    let on = match self.fill.color(x, y, pixel_weights, width) {
+                        Some(on) if pixel.weight() == 0 => {
+                            fill_bits[bit.block as usize][bit.bit as usize] = true;
+                            on
+                        }
+                        _ => pixel.value(),
+                    };
                     self.block_weights[bit.block as usize][bit.bit as usize] =
-                        WeightPixel::new(value, pixel_weights[y * width + x].weight())
+                        WeightPixel::new(mask(x as u16, y as u16) ^ on, pixel.weight())
                 }
             }
         }
 
-        let mut g1_basis = vec![];
-        for i in 0..data_per_g1_block * 8 {
-            let mut v: BitVec = vec![0; data_per_g1_block].into();
-            v.set(i);
-            v.append(&mut remainder(
-                v.as_ref(),
-                &GEN_POLYNOMIALS[ecc_per_block][..ecc_per_block],
-            ));
-            g1_basis.push(v);
-        }
+        let g1_basis = basis(data_per_g1_block, ecc_per_block);
+        let g2_basis = basis(data_per_g1_block + 1, ecc_per_block);
 
-        let mut g2_basis = vec![];
-        for i in 0..(data_per_g1_block + 1) * 8 {
-            let mut v: BitVec = vec![0; data_per_g1_block + 1].into();
-            v.set(i);
-            v.append(&mut remainder(
-                v.as_ref(),
-                &GEN_POLYNOMIALS[ecc_per_block][..ecc_per_block],
-            ));
-            g2_basis.push(v);
-        }
+        let capacity = correction_capacity(self.bit_info.version, self.bit_info.ecl, ecc_per_block);
+        let budget = capacity * self.error_budget as usize / 100;
 
-        for i in 0..group_1_blocks {
-            apply_first_matches(&mut self.blocks[i], &self.block_weights[i], &g1_basis);
-        }
-        for i in 0..group_2_blocks {
-            apply_first_matches(
-                &mut self.blocks[group_1_blocks + i],
-                &self.block_weights[group_1_blocks + i],
-                &g2_basis,
-            );
-        }
+        let mut reports: Vec<BlockReport> = (0..blocks)
+            .map(|i| {
+                let (basis, data_per_block) = if i < group_1_blocks {
+                    (&g1_basis, data_per_g1_block)
+                } else {
+                    (&g2_basis, data_per_g1_block + 1)
+                };
+                let block = &mut self.blocks[i];
+                let weights = &self.block_weights[i];
+                let fill = &fill_bits[i];
+
+                let mut report = solve_block(block, weights, fill, basis.clone());
+                report.capacity = capacity;
+                report.padding_bits = data_per_block * 8 - fixed[i];
+                if budget == 0 {
+                    return report;
+                }
+
+                let errors = worst_codewords(block, weights, fixed[i], budget);
+                if errors.is_empty() {
+                    return report;
+                }
+                // solve again without the sacrificed bits, so they don't use up pivots
+                let in_errors = |j: usize| j >= fixed[i] && errors.contains(&(j / 8));
+                let rest: Vec<_> = (0..weights.len())
+                    .map(|j| {
+                        if in_errors(j) {
+                            WeightPixel::new(false, 0)
+                        } else {
+                            weights[j]
+                        }
+                    })
+                    .collect();
+                let rest_fill: Vec<_> = (0..fill.len()).map(|j| fill[j] && !in_errors(j)).collect();
+                let rest_report = solve_block(block, &rest, &rest_fill, basis.clone());
+
+                for j in (0..weights.len()).filter(|&j| in_errors(j) && weights[j].weight() > 0) {
+                    let bit = 0x80 >> (j % 8);
+                    if weights[j].value() {
+                        block.as_mut()[j / 8] |= bit;
+                    } else {
+                        block.as_mut()[j / 8] &= !bit;
+                    }
+                }
+
+                report.unsatisfied = rest_report.unsatisfied;
+                report.errors = errors;
+                report
+            })
+            .collect();
 
         let mut matrix = Matrix::new(self.bit_info.version, Module(0));
+        let mut heatmap = Matrix::new(self.bit_info.version, 0);
+        let mut total = 0;
 
         for y in 0..width {
             for x in 0..width {
@@ -183,83 +315,569 @@ impl Qart {
                 if !info.module.has(Module::DATA) {
                     matrix.set(x, y, info.module);
                 } else if info.module == Info::REMAINDER {
-                    let on = mask(x as u16, y as u16) ^ pixel_weights[y * width + x].value();
+                    // remainder bits are ignored, so match the pixel directly
+                    let pixel = pixel_weights[y * width + x];
+                    let on = match self.fill.color(x, y, pixel_weights, width) {
+                        Some(on) if pixel.weight() == 0 => on,
+                        _ => pixel.value(),
+                    };
                     matrix.set(x, y, Module::DATA | (Module(on as u8)));
                 } else {
                     let on = mask(x as u16, y as u16)
                         ^ self.blocks[info.block as usize].get(info.bit as usize);
                     matrix.set(x, y, Module::DATA | Module(on as u8));
                 }
+
+                let pixel = pixel_weights[y * width + x];
+                total += pixel.weight() as u32;
+                if matrix.get(x, y).has(Module::ON) != pixel.value() {
+                    heatmap.set(x, y, pixel.weight());
+                    if info.module.has(Module::DATA) && info.module != Info::REMAINDER {
+                        reports[info.block as usize].mismatch += pixel.weight() as u32;
+                    }
+                }
             }
         }
+        let report = QartReport {
+            mismatch: heatmap.value.iter().map(|&weight| weight as u32).sum(),
+            total,
+            blocks: reports,
+            heatmap,
+        };
 
-        QrCode {
+        let qr_code = QrCode {
             matrix,
+            symbol: Symbol::Qr,
             mode: self.bit_info.mode,
             version: self.bit_info.version,
             ecl: self.bit_info.ecl,
             mask: self.bit_info.mask,
+            segments: self.segments,
+        };
+        (qr_code, report)
+    }
+}
+
+/// QArt codes for each frame of an animation, all with the same message, version, and mask.
+///
+/// Modules with pixel weight below `inertia` try to keep their color from the previous frame,
+/// so modules the frames don't care about don't flicker.
+/// Panics if a frame isn't `qr_width * qr_width`, same as `Qart::to_qr_code`.
+#[derive(Debug)]
+pub struct QartSequence<I> {
+    data: Data,
+    mask: Mask,
+    frames: I,
+    fill: Fill,
+    error_budget: u8,
+    inertia: u8,
+    previous: Option<Matrix<Module>>,
+}
+
+impl<I> QartSequence<I> {
+    pub fn new(data: Data, mask: Mask, frames: I) -> Self {
+        QartSequence {
+            data,
+            mask,
+            frames,
+            fill: Fill::Noise(0),
+            error_budget: 0,
+            inertia: 1,
+            previous: None,
         }
     }
+    /// See `Qart::fill`, only the first frame is all fill
+    pub fn fill(mut self, fill: Fill) -> Self {
+        self.fill = fill;
+        self
+    }
+    /// See `Qart::error_budget`
+    pub fn error_budget(mut self, percent: u8) -> Self {
+        self.error_budget = percent;
+        self
+    }
+    /// Pixels with weight below this prefer the previous frame's color over their own value.
+    ///
+    /// Defaults to 1, so only pixels without weight follow the previous frame.
+    pub fn inertia(mut self, weight: u8) -> Self {
+        self.inertia = weight;
+        self
+    }
 }
 
-// based on https://github.com/andrewyur/qart b/c go version too confusing
-//
-// my understanding so far:
-// first, apply matching basis to control a desired bit
-//  -> remove that basis from pool and XOR against all other basis vectors with that bit set
-//  -> therefore, no future basis vector can affect that bit
-// repeat
-//
-// questions:
-// why does greedy work?
-//  -> implies arbitrary subset of basis vectors can mostly span error correction bit vector space
-//    -> is this because num data bits >> num error correction bits?
-// is it worth matching bits in order of location/contrast/importance
-//  -> unordered (current) works suprisingly well
-fn apply_first_matches(
-    block: &mut BitVec,
-    block_weight: &Vec<WeightPixel>,
-    basis_matrix: &Vec<BitVec>,
-) {
-    let mut basis_matrix: Vec<Option<BitVec>> = basis_matrix
-        .iter()
-        .map(|basis| Some(basis.as_ref().to_vec().into()))
-        .collect();
+impl<I, W> Iterator for QartSequence<I>
+where
+    I: Iterator<Item = W>,
+    W: AsRef<[WeightPixel]>,
+{
+    type Item = QrCode;
+
+    fn next(&mut self) -> Option<QrCode> {
+        let frame = self.frames.next()?;
+        let frame = frame.as_ref();
+
+        let pixel_weights: Vec<_> = match &self.previous {
+            // keep the original weight, so previous colors never beat the image
+            Some(previous) => frame
+                .iter()
+                .zip(previous.value.iter())
+                .map(|(&pixel, module)| {
+                    if pixel.weight() < self.inertia {
+                        WeightPixel::new(module.has(Module::ON), pixel.weight().max(1))
+                    } else {
+                        pixel
+                    }
+                })
+                .collect(),
+            None => frame.to_vec(),
+        };
+
+        let qr_code = Qart::new(self.data.clone(), self.mask)
+            .fill(self.fill)
+            .error_budget(self.error_budget)
+            .to_qr_code(&pixel_weights);
+        self.previous = Some(qr_code.matrix.clone());
+        Some(qr_code)
+    }
+}
+
+/// Bits of one error correction block that pixel weights asked for
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BlockReport {
+    /// Sum of pixel weights of this block's modules with the wrong color
+    pub mismatch: u32,
+    /// Bits after the encoded data, which only change how the code looks
+    pub padding_bits: usize,
+    /// Bits with weight above 0, including data bits which are always matched
+    pub requested: usize,
+    /// Requested bits left with the wrong value, b/c they depend on higher weight bits
+    pub unsatisfied: usize,
+    /// Codewords within the block deliberately left wrong, see `Qart::error_budget`.
+    /// Bit `i` of the block is `BitInfo` bit `i`, so codeword `c` is bits `c * 8` to `c * 8 + 7`.
+    pub errors: Vec<usize>,
+    /// Wrong codewords error correction can fix in this block
+    pub capacity: usize,
+}
+
+/// How closely a QArt code matches its pixel weights, and how much error correction is left
+#[derive(Debug, Clone)]
+pub struct QartReport {
+    /// Sum of pixel weights of modules with the wrong color, including function patterns
+    pub mismatch: u32,
+    /// Sum of all pixel weights
+    pub total: u32,
+    pub blocks: Vec<BlockReport>,
+    /// Pixel weight of each module with the wrong color, 0 if it matches
+    pub heatmap: Matrix<u8>,
+}
+
+impl QartReport {
+    /// Weighted share of modules with the right color, 1 if nothing has weight
+    pub fn fidelity(&self) -> f64 {
+        if self.total == 0 {
+            return 1.0;
+        }
+        1.0 - self.mismatch as f64 / self.total as f64
+    }
+    /// Lower weighted mismatch than `other`, compared exactly b/c totals differ across versions
+    pub fn is_better_than(&self, other: &QartReport) -> bool {
+        (self.mismatch as u64 * other.total.max(1) as u64)
+            < (other.mismatch as u64 * self.total.max(1) as u64)
+    }
+    /// Fewest more wrong codewords any block can still correct, e.g. from damage or glare
+    pub fn margin(&self) -> usize {
+        let left = self.blocks.iter().map(|b| b.capacity - b.errors.len());
+        left.min().unwrap_or(0)
+    }
+}
 
-    for (i, pixel) in block_weight.iter().enumerate() {
-        if pixel.weight() == 0 {
-            continue;
+/// Half the error correction codewords, after the misdecode protection codewords
+/// ISO 18004 reserves in the smallest versions
+fn correction_capacity(version: Version, ecl: ECL, ecc_per_block: usize) -> usize {
+    let protection = match (version.0, ecl) {
+        (1, ECL::Low) => 3,
+        (1, ECL::Medium) | (2, ECL::Low) => 2,
+        (1, _) | (3, ECL::Low) => 1,
+        _ => 0,
+    };
+    (ecc_per_block - protection) / 2
+}
+
+/// Up to `budget` codewords with the most weight left wrong, ignoring the first `fixed` bits
+fn worst_codewords(
+    block: &BitVec,
+    weights: &[WeightPixel],
+    fixed: usize,
+    budget: usize,
+) -> Vec<usize> {
+    let mut wrong = vec![0; weights.len() / 8];
+    for (i, weight) in weights.iter().enumerate().skip(fixed) {
+        if block.get(i) != weight.value() {
+            wrong[i / 8] += weight.weight() as usize;
         }
+    }
 
-        let mut found: Option<BitVec> = None;
-        for basis_opt in basis_matrix.iter_mut() {
-            if let Some(basis) = basis_opt {
-                if !basis.get(i) {
-                    continue;
+    let mut codewords: Vec<usize> = (0..wrong.len()).filter(|&c| wrong[c] > 0).collect();
+    codewords.sort_by_key(|&c| Reverse(wrong[c]));
+    codewords.truncate(budget);
+    codewords.sort();
+    codewords
+}
+
+/// Each data bit flipped on its own along with its error correction, as u64 words.
+/// Every codeword of a block is a data block XOR some of these.
+fn basis(data_codewords: usize, ecc_per_block: usize) -> Vec<Vec<u64>> {
+    let encoder = Encoder::get(ecc_per_block);
+    let len = (data_codewords + ecc_per_block) * 8;
+    let mut data = vec![0; data_codewords];
+    let mut ecc = vec![0; ecc_per_block];
+    (0..data_codewords * 8)
+        .map(|i| {
+            data[i / 8] = 1 << (7 - (i % 8));
+            encoder.encode(&data, &mut ecc);
+            data[i / 8] = 0;
+
+            let mut words = vec![0; len.div_ceil(64)];
+            set_bit(&mut words, i);
+            for j in 0..ecc_per_block * 8 {
+                if (ecc[j / 8] >> (7 - (j % 8))) & 1 == 1 {
+                    set_bit(&mut words, data_codewords * 8 + j);
                 }
-                if let Some(found) = found.as_ref() {
-                    let basis = basis.as_mut();
-                    let found = found.as_ref();
+            }
+            words
+        })
+        .collect()
+}
 
-                    for k in 0..basis.len() {
-                        basis[k] ^= found[k]
+// based on https://github.com/andrewyur/qart b/c go version too confusing
+//
+// Gaussian elimination over GF(2), visiting bits from highest to lowest weight:
+// take any remaining basis vector with that bit as the pivot, flip the block with it if needed,
+// then XOR it out of every other remaining vector, so lower weight bits can't change this bit.
+// A bit with no remaining vector depends on higher weight bits, and can't be controlled.
+// `fill` bits have weight 0, and are visited last.
+fn solve_block(
+    block: &mut BitVec,
+    weights: &[WeightPixel],
+    fill: &[bool],
+    mut basis: Vec<Vec<u64>>,
+) -> BlockReport {
+    let mut words = vec![0; weights.len().div_ceil(64)];
+    for i in 0..weights.len() {
+        if block.get(i) {
+            set_bit(&mut words, i);
+        }
+    }
+
+    // stable, so ties keep their order, and data bits come first
+    let mut order: Vec<usize> = (0..weights.len())
+        .filter(|&i| weights[i].weight() > 0)
+        .collect();
+    order.sort_by_key(|&i| Reverse(weights[i].weight()));
+    let requested = order.len();
+    order.extend((0..weights.len()).filter(|&i| weights[i].weight() == 0 && fill[i]));
+
+    let mut unsatisfied = 0;
+    for (n, &i) in order.iter().enumerate() {
+        let wrong = get_bit(&words, i) != weights[i].value();
+        match basis.iter().position(|vector| get_bit(vector, i)) {
+            Some(pivot) => {
+                let pivot = basis.swap_remove(pivot);
+                for vector in basis.iter_mut() {
+                    if get_bit(vector, i) {
+                        xor(vector, &pivot);
                     }
-                } else {
-                    found = basis_opt.take();
+                }
+                if wrong {
+                    xor(&mut words, &pivot);
                 }
             }
+            None if n < requested => unsatisfied += wrong as usize,
+            None => {}
         }
+    }
 
-        if let Some(found) = found {
-            if block.get(i) != pixel.value() {
-                let block = block.as_mut();
-                let found = found.as_ref();
+    for (i, byte) in block.as_mut().iter_mut().enumerate() {
+        *byte = (0..8).fold(0, |byte, j| (byte << 1) | get_bit(&words, i * 8 + j) as u8);
+    }
 
-                for j in 0..block.len() {
-                    block[j] ^= found[j];
-                }
-            }
+    BlockReport {
+        mismatch: 0,
+        padding_bits: 0,
+        requested,
+        unsatisfied,
+        errors: Vec::new(),
+        capacity: 0,
+    }
+}
+
+fn get_bit(words: &[u64], i: usize) -> bool {
+    (words[i / 64] >> (i % 64)) & 1 == 1
+}
+fn set_bit(words: &mut [u64], i: usize) {
+    words[i / 64] |= 1 << (i % 64);
+}
+fn xor(words: &mut [u64], other: &[u64]) {
+    for (word, other) in words.iter_mut().zip(other) {
+        *word ^= other;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::decode, resolve_data, QrOptions};
+
+    const INPUT: &str = "https://github.com/zhengkyl/fuqr";
+
+    fn strict_qart(input: &str, version: usize) -> Qart {
+        let options = QrOptions::new()
+            .min_version(Version(version))
+            .strict_version(true)
+            .strict_ecl(true);
+        let data = resolve_data(input.as_bytes(), &[], Symbol::Qr, &options).unwrap();
+        Qart::new(data, Mask::M0)
+    }
+
+    /// `input` at `version`-L with M0, checked to still decode
+    fn qart_code(
+        input: &str,
+        version: usize,
+        pixel_weights: &[WeightPixel],
+        configure: impl FnOnce(Qart) -> Qart,
+    ) -> (QrCode, QartReport) {
+        let (qr_code, report) =
+            configure(strict_qart(input, version)).to_qr_code_with_report(pixel_weights);
+        assert_eq!(decode(&qr_code.matrix).unwrap().text(), input);
+        (qr_code, report)
+    }
+
+    #[test]
+    fn solve_block_works() {
+        let width = 57;
+        // the left columns are error correction, which can't all be matched
+        let in_square = |x: usize, y: usize| x < 10 && (20..36).contains(&y);
+        let target = |x: usize, y: usize| (x / 4 + y / 4) & 1 == 0;
+
+        let square_mismatches = |square_weight: u8| {
+            let pixel_weights: Vec<_> = (0..width * width)
+                .map(|i| {
+                    let (x, y) = (i % width, i / width);
+                    let weight = if in_square(x, y) { square_weight } else { 1 };
+                    WeightPixel::new(target(x, y), weight)
+                })
+                .collect();
+            let (qr_code, report) = qart_code(INPUT, 10, &pixel_weights, |qart| qart);
+            let reports = report.blocks;
+
+            assert_eq!(reports.len(), 4);
+            assert!(reports.iter().all(|report| report.unsatisfied > 0));
+
+            (0..width * width)
+                .filter(|&i| {
+                    let (x, y) = (i % width, i / width);
+                    let module = qr_code.matrix.get(x, y);
+                    in_square(x, y)
+                        && module.has(Module::DATA)
+                        && module.has(Module::ON) != target(x, y)
+                })
+                .count()
+        };
+
+        assert!(square_mismatches(1) > 0);
+        assert_eq!(square_mismatches(100), 0);
+    }
+
+    #[test]
+    fn report_works() {
+        let width = 57;
+        let pixel_weights: Vec<_> = (0..width * width)
+            .map(|i| WeightPixel::new((i % width / 3 + i / width / 3) & 1 == 0, 50))
+            .collect();
+        let (qr_code, report) = qart_code(INPUT, 10, &pixel_weights, |qart| qart);
+
+        let mut mismatch = 0;
+        for (i, pixel) in pixel_weights.iter().enumerate() {
+            let wrong = qr_code.matrix.value[i].has(Module::ON) != pixel.value();
+            assert_eq!(report.heatmap.value[i], if wrong { 50 } else { 0 });
+            mismatch += wrong as u32 * 50;
         }
+        assert_eq!(report.mismatch, mismatch);
+        assert_eq!(report.total, 50 * (width * width) as u32);
+        assert!(report.fidelity() > 0.5 && report.fidelity() < 1.0);
+
+        // function patterns can't match, so blocks are only part of the mismatch
+        let block_mismatch: u32 = report.blocks.iter().map(|b| b.mismatch).sum();
+        assert!(block_mismatch > 0 && block_mismatch < report.mismatch);
+
+        // 274 data codewords, 4 bit mode, 16 bit length, 32 bytes, and 4 bit terminator
+        let padding_bits: usize = report.blocks.iter().map(|b| b.padding_bits).sum();
+        assert_eq!(padding_bits, 274 * 8 - (4 + 16 + 32 * 8 + 4));
+        assert_eq!(report.margin(), 9);
+
+        // V2 has 7 remainder bits, which aren't part of any block
+        let width = 25;
+        let qart = strict_qart(INPUT, 2);
+        let info = |i: usize| qart.bit_info.matrix.get(i % width, i / width).module;
+        let remainder: Vec<_> = (0..width * width)
+            .filter(|&i| info(i) == Info::REMAINDER)
+            .collect();
+        assert_eq!(remainder.len(), 7);
+        // only data modules have weight, so all mismatch is in blocks or remainder bits
+        let pixel_weights: Vec<_> = (0..width * width)
+            .map(|i| {
+                let weight = if info(i).has(Module::DATA) { 50 } else { 0 };
+                WeightPixel::new((i % width / 3 + i / width / 3) & 1 == 0, weight)
+            })
+            .collect();
+        let (_, report) = qart_code(INPUT, 2, &pixel_weights, |_| qart);
+        let block_mismatch: u32 = report.blocks.iter().map(|b| b.mismatch).sum();
+        let remainder_mismatch: u32 = remainder
+            .iter()
+            .map(|&i| report.heatmap.value[i] as u32)
+            .sum();
+        assert_eq!(remainder_mismatch, 0);
+        assert_eq!(block_mismatch + remainder_mismatch, report.mismatch);
+    }
+
+    #[test]
+    fn sequence_works() {
+        let options = QrOptions::new()
+            .min_version(Version(5))
+            .strict_version(true)
+            .strict_ecl(true);
+        let width = 37;
+        // dark square moving right over low weight grain that changes every frame
+        let frames: Vec<Vec<_>> = (0..8)
+            .map(|frame| {
+                (0..width * width)
+                    .map(|i| {
+                        let (x, y) = (i % width, i / width);
+                        if (10 + frame..18 + frame).contains(&x) && (12..20).contains(&y) {
+                            WeightPixel::new(true, 100)
+                        } else {
+                            WeightPixel::new((x * 7 + y * 13 + frame * 31) % 5 < 2, 5)
+                        }
+                    })
+                    .collect()
+            })
+            .collect();
+
+        let changes = |qr_codes: &[QrCode]| {
+            qr_codes
+                .windows(2)
+                .map(|pair| {
+                    let (a, b) = (&pair[0].matrix.value, &pair[1].matrix.value);
+                    a.iter().zip(b).filter(|(a, b)| a != b).count()
+                })
+                .sum::<usize>()
+        };
+
+        let sequence: Vec<_> = crate::generate_qart_sequence(INPUT, &options, &frames)
+            .unwrap()
+            .inertia(10)
+            .collect();
+        assert_eq!(sequence.len(), 8);
+        for qr_code in sequence.iter() {
+            assert_eq!(qr_code.mask, Mask::M0);
+            assert_eq!(decode(&qr_code.matrix).unwrap().text(), INPUT);
+        }
+
+        let independent: Vec<_> = frames
+            .iter()
+            .map(|frame| qart_code(INPUT, 5, frame, |qart| qart).0)
+            .collect();
+        assert!(changes(&sequence) * 2 < changes(&independent));
+    }
+
+    #[test]
+    fn fill_works() {
+        let width = 37;
+        let qr_code = |fill: Fill, pixel_weights: &[WeightPixel]| {
+            qart_code("fuqr", 5, pixel_weights, |qart| qart.fill(fill))
+                .0
+                .matrix
+        };
+        let dark_ratio = |matrix: &Matrix<Module>| {
+            let data = matrix.value.iter().filter(|m| m.has(Module::DATA));
+            let (dark, total) = data.fold((0, 0), |(dark, total), m| {
+                (dark + m.has(Module::ON) as usize, total + 1)
+            });
+            dark as f64 / total as f64
+        };
+
+        let empty = vec![WeightPixel::new(false, 0); width * width];
+        let noise = qr_code(Fill::Noise(1), &empty);
+        assert_eq!(noise.value, qr_code(Fill::Noise(1), &empty).value);
+        assert_ne!(noise.value, qr_code(Fill::Noise(2), &empty).value);
+        assert!((0.4..0.6).contains(&dark_ratio(&noise)));
+        assert!((0.4..0.6).contains(&dark_ratio(&qr_code(Fill::BlueNoise(1), &empty))));
+        assert_ne!(noise.value, qr_code(Fill::Padding, &empty).value);
+
+        // sparse dark dots spread to the modules around them
+        let dots: Vec<_> = (0..width * width)
+            .map(|i| {
+                let dot = (i % width) % 3 == 1 && (i / width) % 3 == 1;
+                WeightPixel::new(dot, if dot { 10 } else { 0 })
+            })
+            .collect();
+        assert!(dark_ratio(&qr_code(Fill::Neighbours(1), &dots)) > 0.75);
+        assert!(dark_ratio(&qr_code(Fill::Noise(1), &dots)) < 0.65);
+
+        // fill never takes bits from the lowest weight pixels
+        let mismatch = |fill: Fill, pixel_weights: &[WeightPixel]| {
+            let matrix = qr_code(fill, pixel_weights);
+            let pixels = pixel_weights.iter().zip(&matrix.value);
+            pixels
+                .filter(|(pixel, module)| {
+                    pixel.weight() > 0 && module.has(Module::ON) != pixel.value()
+                })
+                .count()
+        };
+        for seed in 0..8 {
+            let faint: Vec<_> = (0..width * width)
+                .map(|i| {
+                    let (x, y) = (i % width, i / width);
+                    let weight = (hash(seed, i as u64) & 3 == 0) as u8;
+                    WeightPixel::new((x / 3 + y / 3) % 2 == 0, weight)
+                })
+                .collect();
+            assert!(mismatch(Fill::Noise(seed), &faint) <= mismatch(Fill::Padding, &faint));
+        }
+    }
+
+    #[test]
+    fn error_budget_works() {
+        let width = 57;
+        // a checkerboard over the whole symbol can't be matched by padding bits alone
+        let pixel_weights: Vec<_> = (0..width * width)
+            .map(|i| WeightPixel::new((i % width + i / width) & 1 == 0, 100))
+            .collect();
+
+        let mismatches = |percent: u8| {
+            let (qr_code, report) =
+                qart_code(INPUT, 10, &pixel_weights, |qart| qart.error_budget(percent));
+
+            let decoded = decode(&qr_code.matrix).unwrap();
+            assert_eq!(report.margin(), 9 - 9 * percent as usize / 100);
+            let reports = report.blocks;
+            for (report, &corrected) in reports.iter().zip(decoded.errors.iter()) {
+                // V10-L has 18 ec codewords per block
+                assert_eq!(report.capacity, 9);
+                assert_eq!(report.errors.len(), corrected);
+                assert!(report.errors.len() <= 9 * percent as usize / 100);
+            }
+
+            let (sum, unsatisfied) = reports.iter().fold((0, 0), |(sum, unsatisfied), r| {
+                (sum + r.errors.len(), unsatisfied + r.unsatisfied)
+            });
+            (sum, unsatisfied)
+        };
+
+        let (errors, unsatisfied) = mismatches(0);
+        assert_eq!(errors, 0);
+        let (budget_errors, budget_unsatisfied) = mismatches(40);
+        assert!(budget_errors > 0);
+        assert!(budget_unsatisfied < unsatisfied);
     }
 }
@@ -1,20 +1,37 @@
 use crate::{
+    constants::{FORMAT_INFO, RMQR_SIZES},
     data::Data,
-    error_correction::ecc_and_sequence,
-    mask::score,
-    matrix::{Matrix, Module},
+    error_correction::{ecc_and_sequence, micro_ecc_and_sequence, rmqr_ecc_and_sequence},
+    mask::{IsoPenalty, MaskSelector},
+    matrix::{format_positions, BitMatrix, Matrix, Module},
+    segment::Segment,
+    template::Template,
+    QrError,
 };
 
 #[cfg(feature = "wasm")]
 use wasm_bindgen::prelude::*;
 
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symbol {
+    Qr,
+    /// Versions M1 - M4 are `Version(1)` - `Version(4)`, and masks are M0 - M3.
+    /// ECI, FNC1, and Structured Append aren't supported.
+    Micro,
+    /// Rectangular Micro QR, versions R7x43 - R17x139 are `Version(1)` - `Version(32)`,
+    /// in the order of `RMQR_SIZES`. Only Medium and High ECL exist, and the mask is always M4.
+    /// ECI, FNC1, and Structured Append aren't supported.
+    Rmqr,
+}
+
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Mode {
     Numeric,
     Alphanumeric,
     Byte,
-    // no plans for Kanji, ECI, StructuredAppend, FNC1,
+    Kanji,
 }
 
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
@@ -39,6 +56,50 @@ impl Version {
     }
 }
 
+/// Extended Channel Interpretation assignment number, 0 - 999999
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Eci(u32);
+
+#[cfg_attr(feature = "wasm", wasm_bindgen)]
+impl Eci {
+    /// Panics if assignment is above 999999, see `try_new`
+    pub fn new(assignment: u32) -> Self {
+        assert!(assignment < 1_000_000);
+        Eci(assignment)
+    }
+    /// `QrError::InvalidEncoding` if assignment is above 999999
+    #[cfg_attr(feature = "wasm", wasm_bindgen(constructor))]
+    pub fn try_new(assignment: u32) -> Result<Eci, QrError> {
+        if assignment < 1_000_000 {
+            Ok(Eci(assignment))
+        } else {
+            Err(QrError::InvalidEncoding)
+        }
+    }
+    pub fn value(&self) -> u32 {
+        self.0
+    }
+}
+
+impl Eci {
+    pub const ISO_8859_1: Eci = Eci(3);
+    pub const SHIFT_JIS: Eci = Eci(20);
+    pub const UTF8: Eci = Eci(26);
+}
+
+/// Function 1 Character, marks data as following an industry standard
+///
+/// GS separators (0x1D) in the input are encoded as `%` in Alphanumeric mode,
+/// so a literal `%` is encoded as `%%`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Fnc1 {
+    /// GS1
+    First,
+    /// Other industry applications, the application indicator is 00 - 99 or an ASCII letter + 100
+    Second(u8),
+}
+
 #[cfg_attr(feature = "wasm", wasm_bindgen)]
 #[derive(Clone, Copy, PartialEq, Eq, Debug)]
 pub enum Mask {
@@ -55,10 +116,12 @@ pub enum Mask {
 #[derive(Debug)]
 pub struct QrCode {
     pub matrix: Matrix<Module>,
+    pub symbol: Symbol,
     pub mode: Mode,
     pub version: Version,
     pub ecl: ECL,
     pub mask: Mask,
+    pub segments: Vec<Segment>,
 }
 
 // vec while in rust only land
@@ -66,75 +129,243 @@ pub struct QrCode {
 
 impl QrCode {
     pub fn new(data: Data, mask: Option<Mask>) -> Self {
+        Self::with_selector(data, mask, &IsoPenalty)
+    }
+
+    /// Same as `new`, but if mask is None, the mask with the lowest cost from `selector` is used
+    pub fn with_selector(mut data: Data, mask: Option<Mask>, selector: &dyn MaskSelector) -> Self {
+        match data.symbol {
+            Symbol::Qr => (),
+            Symbol::Micro => return Self::micro(data, mask, selector),
+            Symbol::Rmqr => return Self::new_rmqr(data),
+        }
+
+        let template = Template::get(data.version);
         let mut qr_code = QrCode {
-            matrix: Matrix::new(data.version, Module(0)),
+            matrix: template.matrix.clone(),
+            symbol: Symbol::Qr,
             mode: data.mode,
             version: data.version,
             ecl: data.ecl,
-            mask: if let Some(mask) = mask {
-                mask
-            } else {
-                Mask::M0
-            },
+            mask: mask.unwrap_or(Mask::M0),
+            segments: std::mem::take(&mut data.segments),
         };
-        qr_code.matrix.set_finder();
-        qr_code.matrix.set_alignment();
-        qr_code.matrix.set_timing();
-        qr_code.matrix.set_format(qr_code.ecl, qr_code.mask);
-        qr_code.matrix.set_version();
 
         let data = ecc_and_sequence(data);
 
+        // unmasked dark modules, with format info left light
+        let mut on = template.on_rows.clone();
+        for (i, &codeword) in data.iter().enumerate() {
+            let mut bits = codeword;
+            while bits != 0 {
+                let j = bits.leading_zeros() as usize;
+                let (x, y) = template.data[i * 8 + j];
+                on.set(x as usize, y as usize, true);
+                bits &= !(0x80 >> j);
+            }
+        }
+
+        let masks = [
+            Mask::M0,
+            Mask::M1,
+            Mask::M2,
+            Mask::M3,
+            Mask::M4,
+            Mask::M5,
+            Mask::M6,
+            Mask::M7,
+        ];
+        let chosen = match mask {
+            Some(mask) => Some(mask),
+            None => qr_code.min_mask_bits(&on, &masks, selector),
+        };
+        match chosen {
+            Some(mask) => {
+                qr_code.mask = mask;
+                let mut masked = on.clone();
+                let pattern = &template.mask_rows[mask as usize];
+                mask_lines(&mut masked, &on, &template.data_rows, pattern);
+                set_dark(&mut qr_code.matrix, &masked, &template.data_rows);
+                qr_code.set_format();
+            }
+            // selector only scores whole matrices
+            None => {
+                set_dark(&mut qr_code.matrix, &on, &template.data_rows);
+                qr_code.apply_mask(qr_code.mask);
+                qr_code.set_format();
+                qr_code.select_mask(&masks, selector);
+            }
+        }
+
+        qr_code
+    }
+
+    /// Micro QR only has masks M0 - M3, any other mask is treated as None and selected automatically
+    pub fn new_micro(data: Data, mask: Option<Mask>) -> Self {
+        Self::micro(data, mask, &IsoPenalty)
+    }
+
+    fn micro(mut data: Data, mask: Option<Mask>, selector: &dyn MaskSelector) -> Self {
+        let mask = mask.filter(|&mask| (mask as u8) < 4);
+        let mut qr_code = QrCode {
+            matrix: Matrix::with_size(data.version.0 * 2 + 9, data.version.0 * 2 + 9, Module(0)),
+            symbol: Symbol::Micro,
+            mode: data.mode,
+            version: data.version,
+            ecl: data.ecl,
+            mask: mask.unwrap_or(Mask::M0),
+            segments: std::mem::take(&mut data.segments),
+        };
+        qr_code.matrix.set_micro_finder();
+        qr_code.matrix.set_micro_timing();
+        qr_code
+            .matrix
+            .set_micro_format(qr_code.version, qr_code.ecl, qr_code.mask);
+
+        let data = micro_ecc_and_sequence(data);
+
         let mut i = 0;
-        qr_code.matrix.set_data(|| {
+        qr_code.matrix.set_micro_data(|| {
             let val = Module::DATA | ((data[i / 8] >> (7 - (i % 8))) & 1).into();
             i += 1;
             val
         });
         qr_code.apply_mask(qr_code.mask);
 
-        if let None = mask {
-            let mut min_score = score(&qr_code.matrix);
-            let mut min_mask = qr_code.mask;
-            for m in [
-                Mask::M1,
-                Mask::M2,
-                Mask::M3,
-                Mask::M4,
-                Mask::M5,
-                Mask::M6,
-                Mask::M7,
-            ] {
-                // undo prev mask
-                qr_code.apply_mask(qr_code.mask);
+        if mask.is_none() {
+            qr_code.select_mask(&[Mask::M0, Mask::M1, Mask::M2, Mask::M3], selector);
+        }
 
-                qr_code.mask = m;
-                qr_code.apply_mask(qr_code.mask);
-                qr_code.matrix.set_format(qr_code.ecl, qr_code.mask);
-                let score = score(&qr_code.matrix);
-                if score < min_score {
-                    min_score = score;
-                    min_mask = qr_code.mask;
-                }
+        qr_code
+    }
+
+    /// rMQR has a single mask pattern, so there is nothing to choose
+    pub fn new_rmqr(mut data: Data) -> Self {
+        let (width, height) = RMQR_SIZES[data.version.0];
+        let mut qr_code = QrCode {
+            matrix: Matrix::with_size(width, height, Module(0)),
+            symbol: Symbol::Rmqr,
+            mode: data.mode,
+            version: data.version,
+            ecl: data.ecl,
+            mask: Mask::M4,
+            segments: std::mem::take(&mut data.segments),
+        };
+        qr_code.matrix.set_rmqr_finder();
+        qr_code.matrix.set_rmqr_alignment();
+        qr_code.matrix.set_rmqr_timing();
+        qr_code.matrix.set_rmqr_format(qr_code.version, qr_code.ecl);
+
+        let data = rmqr_ecc_and_sequence(data);
+
+        let mut i = 0;
+        qr_code.matrix.set_rmqr_data(|| {
+            let val = Module::DATA | ((data[i / 8] >> (7 - (i % 8))) & 1).into();
+            i += 1;
+            val
+        });
+        qr_code.apply_mask(qr_code.mask);
+
+        qr_code
+    }
+
+    /// Expects `masks[0]` to be applied already
+    fn select_mask(&mut self, masks: &[Mask], selector: &dyn MaskSelector) {
+        let min_mask = self.min_mask(masks, selector);
+        if min_mask != self.mask {
+            // undo prev mask
+            self.apply_mask(self.mask);
+
+            self.mask = min_mask;
+            self.apply_mask(self.mask);
+            self.set_format();
+        }
+    }
+
+    /// Tries each mask on the matrix, leaving the last one applied
+    fn min_mask(&mut self, masks: &[Mask], selector: &dyn MaskSelector) -> Mask {
+        let mut min_cost = selector.cost(&self.matrix);
+        let mut min_mask = self.mask;
+        for &m in &masks[1..] {
+            // undo prev mask
+            self.apply_mask(self.mask);
+
+            self.mask = m;
+            self.apply_mask(self.mask);
+            self.set_format();
+            let cost = selector.cost(&self.matrix);
+            if cost < min_cost {
+                min_cost = cost;
+                min_mask = self.mask;
             }
-            if min_mask != qr_code.mask {
-                // undo prev mask
-                qr_code.apply_mask(qr_code.mask);
+        }
+        min_mask
+    }
 
-                qr_code.mask = min_mask;
-                qr_code.apply_mask(qr_code.mask);
+    /// Tries each mask on packed rows and columns of the unmasked dark modules `on`,
+    /// without touching the matrix. None if the selector can't use them.
+    fn min_mask_bits(
+        &self,
+        on: &BitMatrix,
+        masks: &[Mask],
+        selector: &dyn MaskSelector,
+    ) -> Option<Mask> {
+        let template = Template::get(self.version);
+        let width = self.matrix.width;
+        let on_cols = on.transpose();
+
+        let mut rows = on.clone();
+        let mut cols = on_cols.clone();
+        let mut min: Option<(u32, Mask)> = None;
+        for &mask in masks {
+            mask_lines(
+                &mut rows,
+                on,
+                &template.data_rows,
+                &template.mask_rows[mask as usize],
+            );
+            mask_lines(
+                &mut cols,
+                &on_cols,
+                &template.data_cols,
+                &template.mask_cols[mask as usize],
+            );
 
-                qr_code.matrix.set_format(qr_code.ecl, qr_code.mask);
+            let format_info = FORMAT_INFO[self.ecl as usize][mask as usize];
+            for i in 0..15 {
+                let on = (format_info >> i) & 1 == 1;
+                for (x, y) in format_positions(width, i) {
+                    rows.set(x, y, on);
+                    cols.set(y, x, on);
+                }
+            }
+
+            let cost = selector.cost_bits(&rows, &cols)?;
+            if min.is_none_or(|(min_cost, _)| cost < min_cost) {
+                min = Some((cost, mask));
             }
         }
+        min.map(|(_, mask)| mask)
+    }
 
-        qr_code
+    fn set_format(&mut self) {
+        match self.symbol {
+            Symbol::Qr => self.matrix.set_format(self.ecl, self.mask),
+            Symbol::Micro => self
+                .matrix
+                .set_micro_format(self.version, self.ecl, self.mask),
+            Symbol::Rmqr => self.matrix.set_rmqr_format(self.version, self.ecl),
+        }
     }
 
+    /// One module at a time, for Micro QR, rMQR, and selectors without `cost_bits`
     fn apply_mask(&mut self, mask: Mask) {
-        let mask_bit = mask_fn(mask);
+        let mask_bit = match self.symbol {
+            Symbol::Qr | Symbol::Rmqr => mask_fn(mask),
+            Symbol::Micro => micro_mask_fn(mask),
+        };
 
-        for y in 0..self.matrix.width {
+        for y in 0..self.matrix.height {
             for x in 0..self.matrix.width {
                 let module = self.matrix.get_mut(x, y);
                 if module.has(Module::DATA) {
@@ -157,3 +388,56 @@ pub fn mask_fn(mask: Mask) -> fn(u16, u16) -> bool {
         Mask::M7 => |col: u16, row: u16| ((row + col) % 2 + (row * col) % 3) % 2 == 0,
     }
 }
+
+/// `lines` becomes `on` with `pattern` XORed into the `data` modules.
+/// `pattern` is the first 12 lines of a mask, which repeat after that.
+fn mask_lines(lines: &mut BitMatrix, on: &BitMatrix, data: &BitMatrix, pattern: &BitMatrix) {
+    for i in 0..lines.height {
+        let words = on.row(i).iter().zip(data.row(i));
+        for (word, ((on, data), pattern)) in lines
+            .row_mut(i)
+            .iter_mut()
+            .zip(words.zip(pattern.row(i % 12)))
+        {
+            *word = on ^ (pattern & data);
+        }
+    }
+}
+
+/// Sets ON for the data modules of `matrix` that are set in `on`
+fn set_dark(matrix: &mut Matrix<Module>, on: &BitMatrix, data: &BitMatrix) {
+    for y in 0..on.height {
+        for (i, (&on, &data)) in on.row(y).iter().zip(data.row(y)).enumerate() {
+            let mut bits = on & data;
+            while bits != 0 {
+                let x = i * 64 + bits.trailing_zeros() as usize;
+                *matrix.get_mut(x, y) |= Module::ON;
+                bits &= bits - 1;
+            }
+        }
+    }
+}
+
+/// Mask bits of the first 12 rows, or columns if `columns`, which repeat after that
+pub(crate) fn mask_pattern(mask: Mask, width: usize, columns: bool) -> BitMatrix {
+    let mask_bit = mask_fn(mask);
+    let mut pattern = BitMatrix::new(width, 12);
+    for line in 0..12 {
+        for i in 0..width {
+            let (x, y) = if columns { (line, i) } else { (i, line) };
+            pattern.set(i, line, mask_bit(x as u16, y as u16));
+        }
+    }
+    pattern
+}
+
+/// Micro QR masks M0 - M3 are the same patterns as QR masks M1, M4, M6, M7
+pub fn micro_mask_fn(mask: Mask) -> fn(u16, u16) -> bool {
+    match mask {
+        Mask::M0 => mask_fn(Mask::M1),
+        Mask::M1 => mask_fn(Mask::M4),
+        Mask::M2 => mask_fn(Mask::M6),
+        Mask::M3 => mask_fn(Mask::M7),
+        _ => unreachable!("Micro QR only has 4 masks"),
+    }
+}
@@ -40,6 +40,10 @@ impl<'m> RenderData<'m> {
     pub fn width(&self) -> usize {
         (self.qr_code.matrix.width + self.margin * 2) * self.unit
     }
+    /// Same as `width`, except for rMQR
+    pub fn height(&self) -> usize {
+        (self.qr_code.matrix.height + self.margin * 2) * self.unit
+    }
     pub fn unit(mut self, unit: usize) -> Self {
         self.unit = unit;
         self
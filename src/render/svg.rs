@@ -3,18 +3,18 @@ use crate::matrix::Module;
 use super::{RenderData, Toggle};
 
 pub fn render_svg(render: &RenderData) -> String {
-    let mut output = String::with_capacity(40 * (render.width() * render.width()) / 2);
+    let mut output = String::with_capacity(40 * (render.width() * render.height()) / 2);
     output.push_str(&format!(
         r#"<svg xmlns="http://www.w3.org/2000/svg" viewBox="0 0 {} {}">"#,
         render.width(),
-        render.width()
+        render.height()
     ));
 
     if render.toggled(Toggle::Background) {
         output.push_str(&format!(
             r#"<rect width="{}" height="{}" fill="{}"/>"#,
             render.width(),
-            render.width(),
+            render.height(),
             render.background
         ));
     }
@@ -42,7 +42,7 @@ fn render_pixels(render: &RenderData, output: &mut String, on: bool) {
         }
     ));
 
-    for y in 0..render.qr_code.matrix.width {
+    for y in 0..render.qr_code.matrix.height {
         for x in 0..render.qr_code.matrix.width {
             let module_on = render.qr_code.matrix.get(x, y).has(Module::ON);
 
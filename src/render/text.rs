@@ -4,19 +4,20 @@ use super::RenderData;
 
 pub fn render_utf8(render: &RenderData) -> String {
     // row length +1 for \n and take ceil of rows / 2 if odd
-    let mut result = String::with_capacity((render.width() + 1) * (render.width() + 1) / 2);
+    let mut result = String::with_capacity((render.width() + 1) * (render.height() + 1) / 2);
 
     let start = render.margin;
-    let end = render.qr_code.matrix.width + start;
+    let end_x = render.qr_code.matrix.width + start;
+    let end_y = render.qr_code.matrix.height + start;
 
-    for y in (0..render.width()).step_by(2) {
+    for y in (0..render.height()).step_by(2) {
         for x in 0..render.width() {
-            if x < start || x >= end {
+            if x < start || x >= end_x {
                 result.push(' ');
                 continue;
             }
 
-            let top = if y >= start && y < end {
+            let top = if y >= start && y < end_y {
                 render
                     .qr_code
                     .matrix
@@ -26,7 +27,7 @@ pub fn render_utf8(render: &RenderData) -> String {
                 false
             };
 
-            let bot = if y + 1 >= start && y + 1 < end {
+            let bot = if y + 1 >= start && y + 1 < end_y {
                 render
                     .qr_code
                     .matrix
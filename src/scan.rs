@@ -0,0 +1,659 @@
+use crate::{
+    decode::{decode, DecodeError, Decoded},
+    matrix::{Matrix, Module},
+};
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ScanError {
+    /// Length of pixels isn't width * height
+    InvalidImage,
+    /// Couldn't find 3 finder patterns that form a QR code
+    NotFound,
+    /// Found a QR code, but it couldn't be decoded
+    Decode(DecodeError),
+}
+
+impl From<DecodeError> for ScanError {
+    fn from(value: DecodeError) -> Self {
+        ScanError::Decode(value)
+    }
+}
+
+/// Finds and decodes a QR code in a grayscale image, 1 byte per pixel in row major order.
+pub fn scan(pixels: &[u8], width: usize, height: usize) -> Result<Decoded, ScanError> {
+    let mut result = Err(ScanError::NotFound);
+    for matrix in sample(pixels, width, height)? {
+        result = decode(&matrix).map_err(ScanError::from);
+        if result.is_ok() {
+            break;
+        }
+    }
+    result
+}
+
+/// Locates a QR code and samples it into matrices, using the alignment pattern if found,
+/// followed by only the finder patterns.
+pub fn sample(
+    pixels: &[u8],
+    width: usize,
+    height: usize,
+) -> Result<Vec<Matrix<Module>>, ScanError> {
+    if pixels.len() != width * height {
+        return Err(ScanError::InvalidImage);
+    }
+    let image = BitImage {
+        width,
+        height,
+        bits: binarize(pixels, width, height),
+    };
+
+    let [top_left, top_right, bottom_left] = find_finders(&image).ok_or(ScanError::NotFound)?;
+
+    // runs along the axis are the true module size, even if the image is rotated
+    let module_between = |a: &Finder, b: &Finder| {
+        let module = (module_along(&image, a, b).unwrap_or(a.module)
+            + module_along(&image, b, a).unwrap_or(b.module))
+            / 2.0;
+        distance(a.center(), b.center()) / module
+    };
+    let modules_between =
+        (module_between(&top_left, &top_right) + module_between(&top_left, &bottom_left)) / 2.0;
+    let version = ((modules_between + 7.0 - 17.0) / 4.0)
+        .round()
+        .clamp(1.0, 40.0) as usize;
+    let dimension = version * 4 + 17;
+
+    let finder_centers = [
+        ((3.5, 3.5), top_left.center()),
+        ((dimension as f64 - 3.5, 3.5), top_right.center()),
+        ((3.5, dimension as f64 - 3.5), bottom_left.center()),
+    ];
+
+    let mut transforms = Vec::with_capacity(2);
+    if version > 1 {
+        if let Some(alignment) = find_alignment(&image, finder_centers, dimension) {
+            let corner = dimension as f64 - 6.5;
+            transforms.push(Perspective::new(
+                finder_centers.map(|(from, _)| from),
+                (corner, corner),
+                finder_centers.map(|(_, to)| to),
+                alignment,
+            ));
+        }
+    }
+    let corner = dimension as f64 - 3.5;
+    let (tl, tr, bl) = (top_left.center(), top_right.center(), bottom_left.center());
+    transforms.push(Perspective::new(
+        finder_centers.map(|(from, _)| from),
+        (corner, corner),
+        finder_centers.map(|(_, to)| to),
+        (tr.0 + bl.0 - tl.0, tr.1 + bl.1 - tl.1),
+    ));
+
+    Ok(transforms
+        .into_iter()
+        .flatten()
+        .map(|transform| {
+            let mut matrix = Matrix::with_size(dimension, dimension, Module(0));
+            for y in 0..dimension {
+                for x in 0..dimension {
+                    let (px, py) = transform.map(x as f64 + 0.5, y as f64 + 0.5);
+                    if image.get(px, py) {
+                        matrix.set(x, y, Module::ON);
+                    }
+                }
+            }
+            matrix
+        })
+        .collect())
+}
+
+/// Adaptive threshold based on 8x8 blocks, dark pixels are true.
+///
+/// Each pixel is compared to the average of the surrounding 5x5 blocks, and blocks
+/// with little contrast are assumed to be light unless their neighbors are dark.
+pub fn binarize(pixels: &[u8], width: usize, height: usize) -> Vec<bool> {
+    const BLOCK: usize = 8;
+    const MIN_CONTRAST: u8 = 24;
+
+    if width < BLOCK * 5 || height < BLOCK * 5 {
+        let min = pixels.iter().copied().min().unwrap_or(0) as u32;
+        let max = pixels.iter().copied().max().unwrap_or(0) as u32;
+        let threshold = ((min + max) / 2) as u8;
+        return pixels.iter().map(|&p| p <= threshold).collect();
+    }
+
+    let blocks_x = width.div_ceil(BLOCK);
+    let blocks_y = height.div_ceil(BLOCK);
+    // last block is shifted to stay inside the image
+    let offset = |block: usize, len: usize| (block * BLOCK).min(len - BLOCK);
+
+    let mut averages = vec![0u32; blocks_x * blocks_y];
+    for by in 0..blocks_y {
+        let y0 = offset(by, height);
+        for bx in 0..blocks_x {
+            let x0 = offset(bx, width);
+
+            let mut sum = 0;
+            let mut min = u8::MAX;
+            let mut max = u8::MIN;
+            for y in y0..y0 + BLOCK {
+                for &p in &pixels[y * width + x0..y * width + x0 + BLOCK] {
+                    sum += p as u32;
+                    min = min.min(p);
+                    max = max.max(p);
+                }
+            }
+
+            let mut average = sum / (BLOCK * BLOCK) as u32;
+            if max - min <= MIN_CONTRAST {
+                average = min as u32 / 2;
+                if bx > 0 && by > 0 {
+                    let neighbors = (averages[(by - 1) * blocks_x + bx]
+                        + 2 * averages[by * blocks_x + bx - 1]
+                        + averages[(by - 1) * blocks_x + bx - 1])
+                        / 4;
+                    if (min as u32) < neighbors {
+                        average = neighbors;
+                    }
+                }
+            }
+            averages[by * blocks_x + bx] = average;
+        }
+    }
+
+    let mut bits = vec![false; width * height];
+    for by in 0..blocks_y {
+        let y0 = offset(by, height);
+        let cy = by.clamp(2, blocks_y.max(5) - 3);
+        for bx in 0..blocks_x {
+            let x0 = offset(bx, width);
+            let cx = bx.clamp(2, blocks_x.max(5) - 3);
+
+            let mut sum = 0;
+            for y in cy - 2..=cy + 2 {
+                for x in cx - 2..=cx + 2 {
+                    sum += averages[y.min(blocks_y - 1) * blocks_x + x.min(blocks_x - 1)];
+                }
+            }
+            let threshold = sum / 25;
+
+            for y in y0..y0 + BLOCK {
+                for x in x0..x0 + BLOCK {
+                    bits[y * width + x] = pixels[y * width + x] as u32 <= threshold;
+                }
+            }
+        }
+    }
+    bits
+}
+
+struct BitImage {
+    width: usize,
+    height: usize,
+    bits: Vec<bool>,
+}
+
+impl BitImage {
+    /// Pixel containing point, outside is light
+    fn get(&self, x: f64, y: f64) -> bool {
+        if x < 0.0 || y < 0.0 {
+            return false;
+        }
+        let (x, y) = (x as usize, y as usize);
+        x < self.width && y < self.height && self.bits[y * self.width + x]
+    }
+
+    fn get_pixel(&self, x: isize, y: isize) -> Option<bool> {
+        if x < 0 || y < 0 || x as usize >= self.width || y as usize >= self.height {
+            return None;
+        }
+        Some(self.bits[y as usize * self.width + x as usize])
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+struct Finder {
+    x: f64,
+    y: f64,
+    /// Estimated module size in pixels
+    module: f64,
+    /// Number of times this finder was detected
+    count: usize,
+}
+
+impl Finder {
+    fn center(&self) -> (f64, f64) {
+        (self.x, self.y)
+    }
+}
+
+fn distance(a: (f64, f64), b: (f64, f64)) -> f64 {
+    ((a.0 - b.0).powi(2) + (a.1 - b.1).powi(2)).sqrt()
+}
+
+/// Dark, light, dark, light, dark runs are 1:1:3:1:1
+fn is_finder_ratio(runs: [usize; 5]) -> bool {
+    let total: usize = runs.iter().sum();
+    if total < 7 {
+        return false;
+    }
+    let module = total as f64 / 7.0;
+    let max_variance = module / 2.0;
+    [1.0, 1.0, 3.0, 1.0, 1.0]
+        .iter()
+        .zip(runs)
+        .all(|(ratio, run)| (module * ratio - run as f64).abs() < max_variance * ratio)
+}
+
+/// Runs along a line through a dark center pixel at 0, returns center offset and total length
+fn cross_check(
+    line: impl Fn(isize) -> Option<bool>,
+    original_total: usize,
+) -> Option<(f64, usize)> {
+    let mut runs = [0; 5];
+
+    let mut i = 0;
+    for (run, dark) in [(2, true), (1, false), (0, true)] {
+        while line(i) == Some(dark) && runs[run] <= original_total {
+            runs[run] += 1;
+            i -= 1;
+        }
+    }
+    let mut j = 1;
+    for (run, dark) in [(2, true), (3, false), (4, true)] {
+        while line(j) == Some(dark) && runs[run] <= original_total {
+            runs[run] += 1;
+            j += 1;
+        }
+    }
+
+    let total: usize = runs.iter().sum();
+    if runs.contains(&0) || 5 * total.abs_diff(original_total) >= 2 * original_total {
+        return None;
+    }
+    if !is_finder_ratio(runs) {
+        return None;
+    }
+    let center_end = j - (runs[4] + runs[3]) as isize;
+    Some((center_end as f64 - runs[2] as f64 / 2.0, total))
+}
+
+/// Module size of finder `from`, measured along the line towards `to`
+fn module_along(image: &BitImage, from: &Finder, to: &Finder) -> Option<f64> {
+    let length = distance(from.center(), to.center());
+    let (dx, dy) = ((to.x - from.x) / length, (to.y - from.y) / length);
+    let line = |i: isize| {
+        let (x, y) = (from.x + i as f64 * dx, from.y + i as f64 * dy);
+        (x >= 0.0 && y >= 0.0 && (x as usize) < image.width && (y as usize) < image.height)
+            .then(|| image.get(x, y))
+    };
+    let (_, total) = cross_check(line, (from.module * 7.0) as usize)?;
+    Some(total as f64 / 7.0)
+}
+
+fn find_finders(image: &BitImage) -> Option<[Finder; 3]> {
+    let mut finders: Vec<Finder> = Vec::new();
+
+    for y in 0..image.height {
+        let row = &image.bits[y * image.width..(y + 1) * image.width];
+
+        // (dark, end) of each run
+        let mut runs = Vec::new();
+        let mut start = 0;
+        for x in 1..=row.len() {
+            if x == row.len() || row[x] != row[start] {
+                runs.push((row[start], x - start, x));
+                start = x;
+            }
+        }
+
+        for window in runs.windows(5) {
+            if !window[0].0 {
+                continue;
+            }
+            let lengths = [0, 1, 2, 3, 4].map(|i| window[i].1);
+            if !is_finder_ratio(lengths) {
+                continue;
+            }
+            let total: usize = lengths.iter().sum();
+            let center_x = window[2].2 as f64 - window[2].1 as f64 / 2.0;
+
+            let col = center_x as isize;
+            let Some((offset, vertical_total)) =
+                cross_check(|i| image.get_pixel(col, y as isize + i), total)
+            else {
+                continue;
+            };
+            let center_y = y as f64 + offset;
+
+            let row = center_y as isize;
+            let Some((offset, horizontal_total)) =
+                cross_check(|i| image.get_pixel(col + i, row), total)
+            else {
+                continue;
+            };
+            let center_x = col as f64 + offset;
+            let module = (vertical_total + horizontal_total) as f64 / 14.0;
+
+            match finders.iter_mut().find(|finder| {
+                (finder.x - center_x).abs() <= finder.module
+                    && (finder.y - center_y).abs() <= finder.module
+                    && (finder.module - module).abs() <= finder.module.max(1.0)
+            }) {
+                Some(finder) => {
+                    let count = finder.count as f64;
+                    finder.x = (finder.x * count + center_x) / (count + 1.0);
+                    finder.y = (finder.y * count + center_y) / (count + 1.0);
+                    finder.module = (finder.module * count + module) / (count + 1.0);
+                    finder.count += 1;
+                }
+                None => finders.push(Finder {
+                    x: center_x,
+                    y: center_y,
+                    module,
+                    count: 1,
+                }),
+            }
+        }
+    }
+
+    // finders are found on multiple rows, so single detections are likely noise
+    if finders.iter().filter(|finder| finder.count >= 2).count() >= 3 {
+        finders.retain(|finder| finder.count >= 2);
+    }
+    finders.sort_by_key(|finder| std::cmp::Reverse(finder.count));
+    finders.truncate(10);
+
+    // closest to a right isosceles triangle with equal module sizes
+    let mut best = None;
+    let mut best_score = f64::MAX;
+    for i in 0..finders.len() {
+        for j in i + 1..finders.len() {
+            for k in j + 1..finders.len() {
+                let ordered = order_finders([finders[i], finders[j], finders[k]]);
+                let [top_left, top_right, bottom_left] = ordered;
+
+                let module = (top_left.module + top_right.module + bottom_left.module) / 3.0;
+                let module_variance = [top_left, top_right, bottom_left]
+                    .iter()
+                    .map(|finder| (finder.module - module).abs())
+                    .sum::<f64>()
+                    / module;
+
+                let a = distance(top_left.center(), top_right.center());
+                let b = distance(top_left.center(), bottom_left.center());
+                let c = distance(top_right.center(), bottom_left.center());
+                if a < module * 14.0 || b < module * 14.0 {
+                    continue;
+                }
+                let shape = (a - b).abs() / a.max(b) + (c / (a * a + b * b).sqrt() - 1.0).abs();
+
+                let score = module_variance + shape;
+                if score < best_score {
+                    best_score = score;
+                    best = Some(ordered);
+                }
+            }
+        }
+    }
+    best
+}
+
+/// Top left is opposite the longest side, and top right is clockwise from it
+fn order_finders(finders: [Finder; 3]) -> [Finder; 3] {
+    let [a, b, c] = finders;
+    let ab = distance(a.center(), b.center());
+    let bc = distance(b.center(), c.center());
+    let ac = distance(a.center(), c.center());
+
+    let (top_left, mut p, mut q) = if bc >= ab && bc >= ac {
+        (a, b, c)
+    } else if ac >= ab && ac >= bc {
+        (b, a, c)
+    } else {
+        (c, a, b)
+    };
+
+    // y is down, so clockwise is a positive cross product
+    let cross = (p.x - top_left.x) * (q.y - top_left.y) - (p.y - top_left.y) * (q.x - top_left.x);
+    if cross < 0.0 {
+        std::mem::swap(&mut p, &mut q);
+    }
+    [top_left, p, q]
+}
+
+/// Center of the bottom right alignment pattern, searched near where the finders predict
+fn find_alignment(
+    image: &BitImage,
+    finder_centers: [((f64, f64), (f64, f64)); 3],
+    dimension: usize,
+) -> Option<(f64, f64)> {
+    let [(_, top_left), (_, top_right), (_, bottom_left)] = finder_centers;
+    let between = (dimension - 7) as f64;
+    // pixels per module along each axis
+    let u = (
+        (top_right.0 - top_left.0) / between,
+        (top_right.1 - top_left.1) / between,
+    );
+    let v = (
+        (bottom_left.0 - top_left.0) / between,
+        (bottom_left.1 - top_left.1) / between,
+    );
+
+    let corner = between - 3.0;
+    let estimate = (
+        top_left.0 + corner * (u.0 + v.0),
+        top_left.1 + corner * (u.1 + v.1),
+    );
+
+    let module = (u.0.hypot(u.1) + v.0.hypot(v.1)) / 2.0;
+    let radius = (module * 4.0).ceil() as isize;
+
+    // 5x5 modules, ring around the center is light
+    let score = |x: f64, y: f64| {
+        let mut score = 0;
+        for j in -2..=2 {
+            for i in -2..=2 {
+                let expected = i32::max(i32::abs(i), i32::abs(j)) != 1;
+                let (i, j) = (i as f64, j as f64);
+                if image.get(x + i * u.0 + j * v.0, y + i * u.1 + j * v.1) == expected {
+                    score += 1;
+                }
+            }
+        }
+        score
+    };
+
+    let mut best_score = 0;
+    let mut matches = Vec::new();
+    for dy in -radius..=radius {
+        for dx in -radius..=radius {
+            let (x, y) = (
+                estimate.0.floor() + dx as f64 + 0.5,
+                estimate.1.floor() + dy as f64 + 0.5,
+            );
+            let score = score(x, y);
+            if score > best_score {
+                best_score = score;
+                matches.clear();
+            }
+            if score == best_score {
+                matches.push((x, y));
+            }
+        }
+    }
+
+    if best_score < 23 {
+        return None;
+    }
+    let len = matches.len() as f64;
+    Some((
+        matches.iter().map(|m| m.0).sum::<f64>() / len,
+        matches.iter().map(|m| m.1).sum::<f64>() / len,
+    ))
+}
+
+/// Maps module coordinates to image coordinates
+struct Perspective {
+    h: [f64; 8],
+}
+
+impl Perspective {
+    /// 3 finder centers and 1 more point, None if points are degenerate
+    fn new(
+        from: [(f64, f64); 3],
+        from_corner: (f64, f64),
+        to: [(f64, f64); 3],
+        to_corner: (f64, f64),
+    ) -> Option<Self> {
+        let from = [from[0], from[1], from[2], from_corner];
+        let to = [to[0], to[1], to[2], to_corner];
+
+        // u = (h0 x + h1 y + h2) / (h6 x + h7 y + 1), same for v with h3 h4 h5
+        let mut system = [[0.0; 9]; 8];
+        for (i, ((x, y), (u, v))) in from.into_iter().zip(to).enumerate() {
+            system[i * 2] = [x, y, 1.0, 0.0, 0.0, 0.0, -x * u, -y * u, u];
+            system[i * 2 + 1] = [0.0, 0.0, 0.0, x, y, 1.0, -x * v, -y * v, v];
+        }
+
+        // gaussian elimination with partial pivoting
+        for col in 0..8 {
+            let pivot = (col..8).max_by(|&a, &b| {
+                system[a][col]
+                    .abs()
+                    .partial_cmp(&system[b][col].abs())
+                    .unwrap()
+            })?;
+            if system[pivot][col].abs() < 1e-12 {
+                return None;
+            }
+            system.swap(col, pivot);
+
+            let pivot_row = system[col];
+            for (row, equation) in system.iter_mut().enumerate() {
+                if row == col {
+                    continue;
+                }
+                let factor = equation[col] / pivot_row[col];
+                for k in col..9 {
+                    equation[k] -= factor * pivot_row[k];
+                }
+            }
+        }
+
+        let mut h = [0.0; 8];
+        for (i, value) in h.iter_mut().enumerate() {
+            *value = system[i][8] / system[i][i];
+        }
+        Some(Perspective { h })
+    }
+
+    fn map(&self, x: f64, y: f64) -> (f64, f64) {
+        let h = &self.h;
+        let w = h[6] * x + h[7] * y + 1.0;
+        (
+            (h[0] * x + h[1] * y + h[2]) / w,
+            (h[3] * x + h[4] * y + h[5]) / w,
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{generate, qr_code::Version, QrOptions};
+
+    /// Renders matrix with a 4 module margin, `transform` maps pixel to module coordinates
+    fn render(
+        matrix: &Matrix<Module>,
+        size: usize,
+        transform: impl Fn(f64, f64) -> (f64, f64),
+        lighting: impl Fn(usize, usize) -> i32,
+    ) -> Vec<u8> {
+        let mut pixels = vec![0; size * size];
+        for y in 0..size {
+            for x in 0..size {
+                let (mx, my) = transform(x as f64 + 0.5, y as f64 + 0.5);
+                let (mx, my) = (mx - 4.0, my - 4.0);
+                let dark = mx >= 0.0
+                    && my >= 0.0
+                    && (mx as usize) < matrix.width
+                    && (my as usize) < matrix.height
+                    && matrix.get(mx as usize, my as usize).has(Module::ON);
+                let base = if dark { 40 } else { 220 };
+                pixels[y * size + x] = (base + lighting(x, y)).clamp(0, 255) as u8;
+            }
+        }
+        pixels
+    }
+
+    #[test]
+    fn scan_works() {
+        let input = "https://github.com/zhengkyl/fuqr";
+        let qr_code = generate(input, &QrOptions::new()).unwrap();
+        let size = (qr_code.matrix.width + 8) * 5;
+        let pixels = render(&qr_code.matrix, size, |x, y| (x / 5.0, y / 5.0), |_, _| 0);
+
+        let decoded = scan(&pixels, size, size).unwrap();
+        assert_eq!(decoded.text(), input);
+    }
+
+    #[test]
+    fn scan_transformed_works() {
+        let input = "feeling unemployed qr code generator";
+        let qr_code = generate(
+            input,
+            &QrOptions::new()
+                .min_version(Version(7))
+                .strict_version(true),
+        )
+        .unwrap();
+
+        // rotated 20 degrees around the center, 3.7 pixels per module, uneven lighting
+        let modules = (qr_code.matrix.width + 8) as f64;
+        let size = 240;
+        let (sin, cos) = 20f64.to_radians().sin_cos();
+        let scale = 3.7;
+        let center = size as f64 / 2.0;
+        let pixels = render(
+            &qr_code.matrix,
+            size,
+            |x, y| {
+                let (dx, dy) = (x - center, y - center);
+                (
+                    (dx * cos + dy * sin) / scale + modules / 2.0,
+                    (-dx * sin + dy * cos) / scale + modules / 2.0,
+                )
+            },
+            |x, _| x as i32 / 4 - 30,
+        );
+
+        let decoded = scan(&pixels, size, size).unwrap();
+        assert_eq!(decoded.version, Version(7));
+        assert_eq!(decoded.text(), input);
+
+        // perspective, only works if the alignment pattern is found
+        let pixels = render(
+            &qr_code.matrix,
+            size,
+            |x, y| {
+                let w = 1.0 + 0.0012 * x + 0.0005 * y;
+                let (dx, dy) = (x / w - 100.0, y / w - 100.0);
+                (
+                    (dx * cos + dy * sin) / 2.3 + modules / 2.0,
+                    (-dx * sin + dy * cos) / 2.3 + modules / 2.0,
+                )
+            },
+            |_, _| 0,
+        );
+        let decoded = scan(&pixels, size, size).unwrap();
+        assert_eq!(decoded.text(), input);
+
+        assert_eq!(
+            scan(&vec![255; size * size], size, size).unwrap_err(),
+            ScanError::NotFound
+        );
+        assert_eq!(scan(&pixels, size, 1).unwrap_err(), ScanError::InvalidImage);
+    }
+}
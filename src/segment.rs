@@ -0,0 +1,429 @@
+use crate::{
+    data::Data,
+    encoding::{
+        byte_to_b45, encode_alphanumeric, encode_byte, encode_eci, encode_fnc1, encode_kanji,
+        encode_numeric, encode_structured_append, escape_fnc1, num_cci_bits, num_eci_bits,
+        num_micro_cci_bits, num_rmqr_cci_bits,
+    },
+    qr_code::{Eci, Fnc1, Mode, Version},
+    shift_jis::to_shift_jis,
+};
+
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum Segment {
+    Eci(Eci),
+    /// Symbol `index` of `total` symbols, `parity` is XOR of all encoded bytes, see `Segment::parity`
+    StructuredAppend {
+        index: u8,
+        total: u8,
+        parity: u8,
+    },
+    Fnc1(Fnc1),
+    Numeric(String),
+    Alphanumeric(String),
+    Byte(Vec<u8>),
+    Kanji(String),
+}
+
+impl Segment {
+    pub fn new(mode: Mode, text: &str) -> Self {
+        Self::from_bytes(mode, text.as_bytes())
+    }
+
+    /// Input is only required to be valid UTF-8 for text modes
+    pub fn from_bytes(mode: Mode, input: &[u8]) -> Self {
+        match mode {
+            Mode::Numeric => Segment::Numeric(String::from_utf8_lossy(input).into()),
+            Mode::Alphanumeric => Segment::Alphanumeric(String::from_utf8_lossy(input).into()),
+            Mode::Byte => Segment::Byte(input.into()),
+            Mode::Kanji => Segment::Kanji(String::from_utf8_lossy(input).into()),
+        }
+    }
+
+    /// False if text contains characters that can't be encoded in this mode
+    pub fn is_valid(&self) -> bool {
+        match self {
+            Segment::Eci(_) => true,
+            Segment::StructuredAppend { index, total, .. } => index < total && *total <= 16,
+            Segment::Fnc1(Fnc1::First) => true,
+            Segment::Fnc1(Fnc1::Second(application)) => {
+                *application < 100 || (application - 100).is_ascii_alphabetic()
+            }
+            Segment::Numeric(text) => text.bytes().all(|b| b.is_ascii_digit()),
+            Segment::Alphanumeric(text) => text.bytes().all(|b| byte_to_b45(b) < 45),
+            Segment::Byte(_) => true,
+            Segment::Kanji(text) => text.chars().all(|c| to_shift_jis(c).is_some()),
+        }
+    }
+
+    /// None if segment doesn't contain data, e.g. ECI, Structured Append, or FNC1
+    pub fn mode(&self) -> Option<Mode> {
+        match self {
+            Segment::Eci(_) | Segment::StructuredAppend { .. } | Segment::Fnc1(_) => None,
+            Segment::Numeric(_) => Some(Mode::Numeric),
+            Segment::Alphanumeric(_) => Some(Mode::Alphanumeric),
+            Segment::Byte(_) => Some(Mode::Byte),
+            Segment::Kanji(_) => Some(Mode::Kanji),
+        }
+    }
+
+    /// XOR of the bytes this segment encodes, which are Shift JIS for Kanji
+    pub fn parity(&self) -> u8 {
+        let xor = |bytes: &[u8]| bytes.iter().fold(0, |parity, b| parity ^ b);
+        match self {
+            Segment::Eci(_) | Segment::StructuredAppend { .. } | Segment::Fnc1(_) => 0,
+            Segment::Numeric(text) | Segment::Alphanumeric(text) => xor(text.as_bytes()),
+            Segment::Byte(bytes) => xor(bytes),
+            Segment::Kanji(text) => text
+                .chars()
+                .filter_map(to_shift_jis)
+                .fold(0, |parity, c| parity ^ (c >> 8) as u8 ^ c as u8),
+        }
+    }
+
+    /// Length of segment including mode indicator and char count indicator
+    pub fn num_bits(&self, version: Version) -> usize {
+        let header = match self.mode() {
+            Some(mode) => 4 + num_cci_bits(version, mode),
+            None => 0,
+        };
+        header + self.num_payload_bits()
+    }
+
+    /// Same as `num_bits`, but for Micro QR `version` 1 - 4.
+    /// None if the segment can't be encoded in that version.
+    pub fn num_micro_bits(&self, version: Version) -> Option<usize> {
+        let mode = self.mode()?;
+        if !micro_mode_supported(mode, version) {
+            return None;
+        }
+        Some(version.0 - 1 + num_micro_cci_bits(version, mode) + self.num_payload_bits())
+    }
+
+    /// Same as `num_bits`, but for rMQR `version` 1 - 32.
+    /// None for ECI, FNC1, and Structured Append.
+    pub fn num_rmqr_bits(&self, version: Version) -> Option<usize> {
+        let mode = self.mode()?;
+        Some(3 + num_rmqr_cci_bits(version, mode) + self.num_payload_bits())
+    }
+
+    fn num_payload_bits(&self) -> usize {
+        match self {
+            Segment::Eci(eci) => num_eci_bits(*eci),
+            Segment::StructuredAppend { .. } => 4 + 4 + 4 + 8,
+            Segment::Fnc1(Fnc1::First) => 4,
+            Segment::Fnc1(Fnc1::Second(_)) => 4 + 8,
+            Segment::Numeric(text) => {
+                let bits = (text.len() / 3) * 10;
+                match text.len() % 3 {
+                    2 => bits + 7,
+                    1 => bits + 4,
+                    _ => bits,
+                }
+            }
+            Segment::Alphanumeric(text) => (text.len() / 2) * 11 + (text.len() % 2) * 6,
+            Segment::Byte(bytes) => bytes.len() * 8,
+            Segment::Kanji(text) => text.chars().count() * 13,
+        }
+    }
+
+    pub fn encode(&self, data: &mut Data) {
+        match self {
+            Segment::Eci(eci) => encode_eci(data, *eci),
+            Segment::StructuredAppend {
+                index,
+                total,
+                parity,
+            } => encode_structured_append(data, *index, *total, *parity),
+            Segment::Fnc1(fnc1) => encode_fnc1(data, *fnc1),
+            Segment::Numeric(text) => encode_numeric(data, text),
+            Segment::Alphanumeric(text) => encode_alphanumeric(data, text),
+            Segment::Byte(bytes) => encode_byte(data, bytes),
+            Segment::Kanji(text) => encode_kanji(data, text),
+        }
+    }
+}
+
+const MODES: [Mode; 4] = [Mode::Numeric, Mode::Alphanumeric, Mode::Byte, Mode::Kanji];
+
+/// M1 is only Numeric, M2 adds Alphanumeric, M3 and M4 add Byte and Kanji
+fn micro_mode_supported(mode: Mode, version: Version) -> bool {
+    match mode {
+        Mode::Numeric => true,
+        Mode::Alphanumeric => version.0 >= 2,
+        Mode::Byte | Mode::Kanji => version.0 >= 3,
+    }
+}
+
+/// Cost in 1/6 bits, b/c numeric is 10 bits per 3 chars and alphanumeric is 11 bits per 2 chars
+fn char_cost(mode: Mode, c: &[u8], fnc1: bool) -> Option<usize> {
+    match mode {
+        Mode::Numeric if c.len() == 1 && c[0].is_ascii_digit() => Some(20),
+        // % is escaped as %%
+        Mode::Alphanumeric if fnc1 && c == b"%" => Some(66),
+        // GS separator is encoded as %
+        Mode::Alphanumeric if fnc1 && c == b"\x1D" => Some(33),
+        Mode::Alphanumeric if c.len() == 1 && byte_to_b45(c[0]) < 45 => Some(33),
+        Mode::Byte => Some(c.len() * 8 * 6),
+        Mode::Kanji => match std::str::from_utf8(c) {
+            Ok(c) if c.chars().all(|c| to_shift_jis(c).is_some()) => Some(13 * 6),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+/// Splits text into the segments that take the fewest bits at `version`.
+///
+/// The result is only optimal for versions with the same char count indicator lengths,
+/// so 1-9, 10-26, or 27-40.
+pub fn optimal_segments(text: &str, version: Version) -> Vec<Segment> {
+    optimal_segments_bytes(text.as_bytes(), version, false)
+}
+
+/// Same as `optimal_segments`, but Kanji is only considered if input is valid UTF-8.
+///
+/// If `fnc1` is true, GS separators (0x1D) can be encoded in Alphanumeric mode, see `Fnc1`.
+pub fn optimal_segments_bytes(input: &[u8], version: Version, fnc1: bool) -> Vec<Segment> {
+    let head_costs = MODES.map(|mode| Some((4 + num_cci_bits(version, mode)) * 6));
+    split(input, head_costs, fnc1).expect("byte mode always valid")
+}
+
+/// Same as `optimal_segments_bytes`, but for Micro QR `version` 1 - 4.
+///
+/// None if some char can't be encoded by the modes in that version.
+pub fn optimal_micro_segments(input: &[u8], version: Version) -> Option<Vec<Segment>> {
+    let head_costs = MODES.map(|mode| {
+        micro_mode_supported(mode, version)
+            .then(|| (version.0 - 1 + num_micro_cci_bits(version, mode)) * 6)
+    });
+    split(input, head_costs, false)
+}
+
+/// Same as `optimal_segments_bytes`, but for rMQR `version` 1 - 32.
+pub fn optimal_rmqr_segments(input: &[u8], version: Version) -> Vec<Segment> {
+    let head_costs = MODES.map(|mode| Some((3 + num_rmqr_cci_bits(version, mode)) * 6));
+    split(input, head_costs, false).expect("byte mode always valid")
+}
+
+/// `head_costs` is None for modes that can't be used
+fn split(input: &[u8], head_costs: [Option<usize>; 4], fnc1: bool) -> Option<Vec<Segment>> {
+    // byte ranges of each char, or each byte if not utf8
+    let chars: Vec<&[u8]> = match std::str::from_utf8(input) {
+        Ok(text) => text
+            .char_indices()
+            .map(|(i, c)| &input[i..i + c.len_utf8()])
+            .collect(),
+        Err(_) => input.chunks(1).collect(),
+    };
+    if chars.is_empty() {
+        return Some(Vec::new());
+    }
+
+    // costs[m] is the min cost to encode chars so far, ending in mode m
+    let mut costs = head_costs.map(|cost| cost.unwrap_or(usize::MAX));
+    // char_modes[i][m] is the mode of char i, if char i + 1 uses mode m
+    let mut char_modes = vec![[None; 4]; chars.len()];
+
+    for (i, &c) in chars.iter().enumerate() {
+        let mut next_costs = [usize::MAX; 4];
+
+        for m in 0..MODES.len() {
+            if costs[m] == usize::MAX {
+                continue;
+            }
+            if let Some(cost) = char_cost(MODES[m], c, fnc1) {
+                next_costs[m] = costs[m] + cost;
+                char_modes[i][m] = Some(m);
+            }
+        }
+
+        // start a new segment after this char
+        for to in 0..MODES.len() {
+            let Some(head_cost) = head_costs[to] else {
+                continue;
+            };
+            for from in 0..MODES.len() {
+                if char_modes[i][from] != Some(from) {
+                    continue;
+                }
+                let cost = next_costs[from].div_ceil(6) * 6 + head_cost;
+                if cost < next_costs[to] {
+                    next_costs[to] = cost;
+                    char_modes[i][to] = Some(from);
+                }
+            }
+        }
+
+        costs = next_costs;
+    }
+
+    let mut mode = 0;
+    for m in 1..MODES.len() {
+        if costs[m].div_ceil(6) < costs[mode].div_ceil(6) {
+            mode = m;
+        }
+    }
+    if costs[mode] == usize::MAX {
+        return None;
+    }
+
+    let mut modes = vec![0; chars.len()];
+    for i in (0..chars.len()).rev() {
+        mode = char_modes[i][mode]?;
+        modes[i] = mode;
+    }
+
+    let mut segments = Vec::new();
+    let mut start = 0;
+    let mut byte_start = 0;
+    let mut byte_end = 0;
+    for i in 0..=chars.len() {
+        if i == chars.len() || modes[i] != modes[start] {
+            let bytes = &input[byte_start..byte_end];
+            segments.push(match MODES[modes[start]] {
+                Mode::Alphanumeric if fnc1 => Segment::Alphanumeric(escape_fnc1(bytes)),
+                mode => Segment::from_bytes(mode, bytes),
+            });
+            start = i;
+            byte_start = byte_end;
+        }
+        if i < chars.len() {
+            byte_end += chars[i].len();
+        }
+    }
+    Some(segments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::qr_code::ECL;
+
+    fn total_bits(segments: &[Segment], version: Version) -> usize {
+        segments.iter().map(|s| s.num_bits(version)).sum()
+    }
+
+    #[test]
+    fn single_mode_works() {
+        assert_eq!(
+            optimal_segments("0123456789", Version(1)),
+            vec![Segment::Numeric("0123456789".into())]
+        );
+        assert_eq!(
+            optimal_segments("HELLO WORLD", Version(1)),
+            vec![Segment::Alphanumeric("HELLO WORLD".into())]
+        );
+        assert_eq!(
+            optimal_segments("hello world", Version(1)),
+            vec![Segment::Byte("hello world".into())]
+        );
+        assert_eq!(
+            optimal_segments("点茗", Version(1)),
+            vec![Segment::Kanji("点茗".into())]
+        );
+        assert_eq!(optimal_segments("", Version(1)), vec![]);
+    }
+
+    #[test]
+    fn mixed_modes_work() {
+        let text = "HTTPS://EXAMPLE.COM/ORDER/123456789012?q=abc";
+        let segments = optimal_segments(text, Version(3));
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Alphanumeric("HTTPS://EXAMPLE.COM/ORDER/".into()),
+                Segment::Numeric("123456789012".into()),
+                Segment::Byte("?q=abc".into()),
+            ]
+        );
+
+        let byte_bits = Segment::Byte(text.into()).num_bits(Version(3));
+        assert!(total_bits(&segments, Version(3)) < byte_bits);
+    }
+
+    #[test]
+    fn short_runs_are_merged() {
+        // switching modes for 2 digits costs more than it saves
+        assert_eq!(
+            optimal_segments("abc12def", Version(1)),
+            vec![Segment::Byte("abc12def".into())]
+        );
+    }
+
+    #[test]
+    fn binary_input_works() {
+        let input = [b'1', b'2', b'3', b'4', b'5', b'6', b'7', b'8', 0xFF, 0xFE];
+        assert_eq!(
+            optimal_segments_bytes(&input, Version(1), false),
+            vec![
+                Segment::Numeric("12345678".into()),
+                Segment::Byte(vec![0xFF, 0xFE]),
+            ]
+        );
+
+        // invalid utf8, so no Kanji
+        let mut input = "点茗".as_bytes().to_vec();
+        input.push(0xFF);
+        assert_eq!(
+            optimal_segments_bytes(&input, Version(1), false),
+            vec![Segment::Byte(input)]
+        );
+    }
+
+    #[test]
+    fn fnc1_works() {
+        let input = "01049123451234591597033130128\x1D10ABC123";
+        assert_eq!(
+            optimal_segments_bytes(input.as_bytes(), Version(1), true),
+            vec![
+                Segment::Numeric("01049123451234591597033130128".into()),
+                Segment::Alphanumeric("%10ABC123".into()),
+            ]
+        );
+        assert_eq!(
+            optimal_segments_bytes(input.as_bytes(), Version(1), false),
+            vec![
+                Segment::Numeric("01049123451234591597033130128".into()),
+                Segment::Byte("\x1D".into()),
+                Segment::Alphanumeric("10ABC123".into()),
+            ]
+        );
+    }
+
+    #[test]
+    fn micro_segments_work() {
+        assert_eq!(
+            optimal_micro_segments(b"0123456789", Version(1)),
+            Some(vec![Segment::Numeric("0123456789".into())])
+        );
+        // M1 is only Numeric, M2 has no Byte
+        assert_eq!(optimal_micro_segments(b"A1", Version(1)), None);
+        assert_eq!(optimal_micro_segments(b"a1", Version(2)), None);
+
+        let segments = optimal_micro_segments(b"a1234567", Version(3)).unwrap();
+        assert_eq!(
+            segments,
+            vec![
+                Segment::Byte("a".into()),
+                Segment::Numeric("1234567".into()),
+            ]
+        );
+        assert_eq!(Segment::Eci(Eci::UTF8).num_micro_bits(Version(4)), None);
+    }
+
+    #[test]
+    fn segmented_data_works() {
+        let text = "HTTPS://EXAMPLE.COM/ORDER/123456789012?q=abc";
+        let data = Data::new_segmented(text, None, Version(1), false, ECL::Low, true).unwrap();
+        assert_eq!(data.mode, Mode::Byte);
+        assert_eq!(data.segments, optimal_segments(text, data.version));
+        assert_eq!(data.bits.len(), total_bits(&data.segments, data.version));
+
+        // needs a version >= 10, where numeric char count indicator is longer
+        let text = "1".repeat(600);
+        let data = Data::new_segmented(&text, None, Version(1), false, ECL::Low, true).unwrap();
+        assert_eq!(data.version, Version(10));
+        assert_eq!(data.mode, Mode::Numeric);
+    }
+}
@@ -0,0 +1,94 @@
+use std::sync::OnceLock;
+
+use crate::{
+    matrix::{format_positions, BitMatrix, Matrix, Module},
+    qr_code::{mask_pattern, Mask, Version},
+};
+
+/// Function patterns and data module order of a QR version, which don't depend on ECL or mask
+#[derive(Debug)]
+pub struct Template {
+    /// Finder, alignment, timing, and version info placed, and data modules marked as DATA.
+    /// Format info is reserved as light modules, except the always dark module.
+    pub matrix: Matrix<Module>,
+    /// Data module positions (x, y) in placement order, including remainder bits
+    pub data: Vec<(u8, u8)>,
+    /// Dark modules of `matrix` as packed rows
+    pub on_rows: BitMatrix,
+    /// Data modules as packed rows, and as columns
+    pub data_rows: BitMatrix,
+    pub data_cols: BitMatrix,
+    /// `mask_pattern` of each mask, as rows and as columns
+    pub mask_rows: [BitMatrix; 8],
+    pub mask_cols: [BitMatrix; 8],
+}
+
+static TEMPLATES: [OnceLock<Template>; 41] = [const { OnceLock::new() }; 41];
+
+impl Template {
+    /// Built on first use, then cached
+    pub fn get(version: Version) -> &'static Template {
+        TEMPLATES[version.0].get_or_init(|| Template::new(version))
+    }
+
+    fn new(version: Version) -> Self {
+        let mut matrix = Matrix::new(version, Module(0));
+        matrix.set_finder();
+        matrix.set_alignment();
+        matrix.set_timing();
+        matrix.set_version();
+
+        // reserve format info, so data skips it
+        for i in 0..15 {
+            let [(x, y), (copy_x, copy_y)] = format_positions(matrix.width, i);
+            matrix.set(x, y, Module::FORMAT);
+            matrix.set(copy_x, copy_y, Module::FORMAT_COPY);
+        }
+        matrix.set(8, matrix.width - 8, Module::FORMAT_COPY | Module::ON);
+
+        let data: Vec<_> = matrix
+            .data_positions()
+            .into_iter()
+            .map(|(x, y)| (x as u8, y as u8))
+            .collect();
+        for &(x, y) in data.iter() {
+            matrix.set(x as usize, y as usize, Module::DATA);
+        }
+
+        let masks = [
+            Mask::M0,
+            Mask::M1,
+            Mask::M2,
+            Mask::M3,
+            Mask::M4,
+            Mask::M5,
+            Mask::M6,
+            Mask::M7,
+        ];
+        let data_rows = BitMatrix::from_matrix(&matrix, Module::DATA);
+        Template {
+            on_rows: BitMatrix::from_matrix(&matrix, Module::ON),
+            data_cols: data_rows.transpose(),
+            data_rows,
+            mask_rows: masks.map(|mask| mask_pattern(mask, matrix.width, false)),
+            mask_cols: masks.map(|mask| mask_pattern(mask, matrix.width, true)),
+            matrix,
+            data,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::constants::NUM_DATA_MODULES;
+
+    #[test]
+    fn template_works() {
+        for (version, &modules) in NUM_DATA_MODULES.iter().enumerate().skip(1) {
+            let template = Template::get(Version(version));
+            assert_eq!(template.data.len(), modules as usize);
+            assert!(std::ptr::eq(template, Template::get(Version(version))));
+        }
+    }
+}
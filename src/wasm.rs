@@ -18,6 +18,16 @@ pub fn generate(input: &str, qr_options: &QrOptions) -> Result<JsValue, QrError>
     Ok(qr_code_to_obj(qr_code))
 }
 
+#[wasm_bindgen(js_name = generateBytes)]
+pub fn generate_bytes(input: &[u8], qr_options: &QrOptions) -> Result<JsValue, QrError> {
+    console_error_panic_hook::set_once();
+    let qr_code = match crate::generate_bytes(input, qr_options) {
+        Ok(m) => m,
+        Err(e) => return Err(e),
+    };
+    Ok(qr_code_to_obj(qr_code))
+}
+
 #[wasm_bindgen(js_name = generateQart)]
 pub fn generate_qart(
     input: &str,
@@ -33,6 +43,21 @@ pub fn generate_qart(
     Ok(qr_code_to_obj(qr_code))
 }
 
+#[wasm_bindgen(js_name = generateQartBytes)]
+pub fn generate_qart_bytes(
+    input: &[u8],
+    qr_options: &QrOptions,
+    pixel_weights: &[u8],
+) -> Result<JsValue, QartError> {
+    console_error_panic_hook::set_once();
+    let pixel_weights = unsafe { std::mem::transmute(pixel_weights) };
+    let qr_code = match crate::generate_qart_bytes(input, qr_options, pixel_weights) {
+        Ok(m) => m,
+        Err(e) => return Err(e),
+    };
+    Ok(qr_code_to_obj(qr_code))
+}
+
 fn qr_code_to_obj(qr_code: QrCode) -> JsValue {
     let u = js_sys::Uint8Array::new_with_length(qr_code.matrix.value.len() as u32);
     u.copy_from(unsafe { std::mem::transmute(qr_code.matrix.value.as_slice()) });
@@ -40,6 +65,13 @@ fn qr_code_to_obj(qr_code: QrCode) -> JsValue {
     let obj = js_sys::Object::new();
     // If these error, it's not recoverable
     let _ = js_sys::Reflect::set(&obj, &"matrix".into(), &u);
+    let _ = js_sys::Reflect::set(&obj, &"width".into(), &JsValue::from(qr_code.matrix.width));
+    let _ = js_sys::Reflect::set(
+        &obj,
+        &"height".into(),
+        &JsValue::from(qr_code.matrix.height),
+    );
+    let _ = js_sys::Reflect::set(&obj, &"symbol".into(), &JsValue::from(qr_code.symbol));
     let _ = js_sys::Reflect::set(&obj, &"mode".into(), &JsValue::from(qr_code.mode));
     let _ = js_sys::Reflect::set(&obj, &"version".into(), &JsValue::from(qr_code.version.0));
     let _ = js_sys::Reflect::set(&obj, &"ecl".into(), &JsValue::from(qr_code.ecl));
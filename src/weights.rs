@@ -0,0 +1,334 @@
+use crate::{qart::WeightPixel, qr_code::Version};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PixelFormat {
+    Luma,
+    Rgb,
+    Rgba,
+}
+
+impl PixelFormat {
+    pub fn channels(self) -> usize {
+        match self {
+            PixelFormat::Luma => 1,
+            PixelFormat::Rgb => 3,
+            PixelFormat::Rgba => 4,
+        }
+    }
+}
+
+/// Row major 8-bit pixels, such as `image::GrayImage` or `image::RgbaImage` as raw bytes
+#[derive(Debug, Clone, Copy)]
+pub struct Image<'a> {
+    pub pixels: &'a [u8],
+    pub width: usize,
+    pub height: usize,
+    pub format: PixelFormat,
+}
+
+impl<'a> Image<'a> {
+    /// Panics if `pixels` doesn't match the size and format
+    pub fn new(pixels: &'a [u8], width: usize, height: usize, format: PixelFormat) -> Self {
+        let image = Image {
+            pixels,
+            width,
+            height,
+            format,
+        };
+        assert!(image.is_valid(), "pixels don't match image size");
+        image
+    }
+    pub fn is_valid(&self) -> bool {
+        self.width > 0
+            && self.height > 0
+            && self.pixels.len() == self.width * self.height * self.format.channels()
+    }
+    /// 0 is black, transparent pixels are white
+    fn luma(&self, x: usize, y: usize) -> u32 {
+        let i = (y * self.width + x) * self.format.channels();
+        let p = &self.pixels[i..i + self.format.channels()];
+        // ITU-R BT.601, same as image crate
+        let rgb = |r: u8, g: u8, b: u8| (r as u32 * 299 + g as u32 * 587 + b as u32 * 114) / 1000;
+        match self.format {
+            PixelFormat::Luma => p[0] as u32,
+            PixelFormat::Rgb => rgb(p[0], p[1], p[2]),
+            PixelFormat::Rgba => {
+                let alpha = p[3] as u32;
+                (rgb(p[0], p[1], p[2]) * alpha + 255 * (255 - alpha)) / 255
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Resample {
+    /// Pixel at the center of each module
+    Nearest,
+    /// Average of all pixels covered by each module
+    Area,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Binarize {
+    /// Dark if below the threshold
+    Threshold,
+    /// Floyd-Steinberg error diffusion around the threshold, for gradients and photos
+    Dither,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WeightScheme {
+    /// Same weight everywhere, 0 - 127
+    Uniform(u8),
+    /// Distance from the threshold, so mid grays matter least
+    Contrast,
+    /// Sobel gradient, strongest edge gets 127 and flat areas get 0
+    Edge,
+    /// 127 at the center down to 1 at the corners
+    Center,
+}
+
+/// Converts an image into `WeightPixel`s for `generate_qart`. Dark pixels become dark modules.
+///
+/// The image is stretched to cover the whole symbol, so crop or pad it first to keep its aspect.
+#[derive(Debug, Clone, Copy)]
+pub struct ImageWeights {
+    resample: Resample,
+    binarize: Binarize,
+    scheme: WeightScheme,
+    threshold: u8,
+}
+
+impl ImageWeights {
+    pub fn new() -> Self {
+        ImageWeights {
+            resample: Resample::Area,
+            binarize: Binarize::Threshold,
+            scheme: WeightScheme::Uniform(127),
+            threshold: 128,
+        }
+    }
+    pub fn resample(mut self, resample: Resample) -> Self {
+        self.resample = resample;
+        self
+    }
+    pub fn binarize(mut self, binarize: Binarize) -> Self {
+        self.binarize = binarize;
+        self
+    }
+    pub fn scheme(mut self, scheme: WeightScheme) -> Self {
+        self.scheme = scheme;
+        self
+    }
+    /// Luma below this is dark, defaults to 128
+    pub fn threshold(mut self, threshold: u8) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// `qr_width * qr_width` weights for a QR code of `version`
+    pub fn weights(&self, image: &Image, version: Version) -> Vec<WeightPixel> {
+        assert!(image.is_valid(), "pixels don't match image size");
+        let width = version.0 * 4 + 17;
+        let luma = self.resampled(image, width);
+        let values = self.binarized(&luma, width);
+        let weights = self.weighted(&luma, width);
+
+        values
+            .into_iter()
+            .zip(weights)
+            .map(|(value, weight)| WeightPixel::new(value, weight))
+            .collect()
+    }
+
+    fn resampled(&self, image: &Image, width: usize) -> Vec<u32> {
+        // module i covers source pixels start(i) to start(i + 1), at least 1 pixel
+        let range = |i: usize, len: usize| {
+            let start = i * len / width;
+            let end = ((i + 1) * len / width).max(start + 1);
+            start..end
+        };
+        let mut luma = Vec::with_capacity(width * width);
+        for y in 0..width {
+            for x in 0..width {
+                luma.push(match self.resample {
+                    Resample::Nearest => image.luma(
+                        (x * 2 + 1) * image.width / (width * 2),
+                        (y * 2 + 1) * image.height / (width * 2),
+                    ),
+                    Resample::Area => {
+                        let (xs, ys) = (range(x, image.width), range(y, image.height));
+                        let count = (xs.len() * ys.len()) as u32;
+                        let sum: u32 = ys
+                            .flat_map(|sy| xs.clone().map(move |sx| image.luma(sx, sy)))
+                            .sum();
+                        (sum + count / 2) / count
+                    }
+                });
+            }
+        }
+        luma
+    }
+
+    fn binarized(&self, luma: &[u32], width: usize) -> Vec<bool> {
+        let threshold = self.threshold as i32;
+        match self.binarize {
+            Binarize::Threshold => luma.iter().map(|&l| (l as i32) < threshold).collect(),
+            Binarize::Dither => {
+                let mut values = luma.iter().map(|&l| l as i32 * 16).collect::<Vec<_>>();
+                let mut dark = vec![false; luma.len()];
+                for y in 0..width {
+                    for x in 0..width {
+                        let i = y * width + x;
+                        dark[i] = values[i] < threshold * 16;
+                        let error = values[i] - if dark[i] { 0 } else { 255 * 16 };
+                        let mut spread = |dx: isize, dy: usize, share: i32| {
+                            let nx = x as isize + dx;
+                            if nx >= 0 && (nx as usize) < width && y + dy < width {
+                                values[(y + dy) * width + nx as usize] += error * share / 16;
+                            }
+                        };
+                        spread(1, 0, 7);
+                        spread(-1, 1, 3);
+                        spread(0, 1, 5);
+                        spread(1, 1, 1);
+                    }
+                }
+                dark
+            }
+        }
+    }
+
+    fn weighted(&self, luma: &[u32], width: usize) -> Vec<u8> {
+        match self.scheme {
+            WeightScheme::Uniform(weight) => vec![weight.min(127); luma.len()],
+            WeightScheme::Contrast => {
+                let threshold = self.threshold as u32;
+                let max = threshold.max(255 - threshold).max(1);
+                luma.iter()
+                    .map(|&l| (l.abs_diff(threshold) * 127 / max).min(127) as u8)
+                    .collect()
+            }
+            WeightScheme::Edge => {
+                // edges are clamped, so the border isn't an edge
+                let at = |x: isize, y: isize| {
+                    let clamp = |i: isize| i.clamp(0, width as isize - 1) as usize;
+                    luma[clamp(y) * width + clamp(x)] as i32
+                };
+                let magnitudes: Vec<u32> = (0..luma.len())
+                    .map(|i| {
+                        let (x, y) = ((i % width) as isize, (i / width) as isize);
+                        let gx = at(x + 1, y - 1) + 2 * at(x + 1, y) + at(x + 1, y + 1)
+                            - at(x - 1, y - 1)
+                            - 2 * at(x - 1, y)
+                            - at(x - 1, y + 1);
+                        let gy = at(x - 1, y + 1) + 2 * at(x, y + 1) + at(x + 1, y + 1)
+                            - at(x - 1, y - 1)
+                            - 2 * at(x, y - 1)
+                            - at(x + 1, y - 1);
+                        gx.unsigned_abs() + gy.unsigned_abs()
+                    })
+                    .collect();
+                let max = magnitudes.iter().copied().max().unwrap_or(0).max(1);
+                magnitudes.iter().map(|&m| (m * 127 / max) as u8).collect()
+            }
+            WeightScheme::Center => {
+                // squared distance from the center, in half modules
+                let center = width as i64 - 1;
+                let max = (2 * center * center) as f64;
+                (0..luma.len())
+                    .map(|i| {
+                        let dx = (i % width) as i64 * 2 - center;
+                        let dy = (i / width) as i64 * 2 - center;
+                        let distance = ((dx * dx + dy * dy) as f64 / max).sqrt();
+                        (127.0 - 126.0 * distance).round() as u8
+                    })
+                    .collect()
+            }
+        }
+    }
+}
+
+impl Default for ImageWeights {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{decode::decode, generate_qart_image, QartError, QrOptions};
+
+    #[test]
+    fn weights_works() {
+        let version = Version(1);
+        let width = 21;
+
+        // left half black, right half white, at 4x the symbol size
+        let pixels: Vec<u8> = (0..84 * 84)
+            .map(|i| if i % 84 < 42 { 0 } else { 255 })
+            .collect();
+        let image = Image::new(&pixels, 84, 84, PixelFormat::Luma);
+        let weights = ImageWeights::new().weights(&image, version);
+        assert_eq!(weights.len(), width * width);
+        for (i, weight) in weights.iter().enumerate() {
+            // the middle column is half and half, which rounds to white
+            assert_eq!(weight.value(), i % width < 10);
+            assert_eq!(weight.weight(), 127);
+        }
+        let edges = ImageWeights::new()
+            .scheme(WeightScheme::Edge)
+            .weights(&image, version);
+        assert_eq!(edges[width * 5 + 10].weight(), 127);
+        assert_eq!(edges[width * 5 + 2].weight(), 0);
+
+        // 50% gray dithers to about half dark, with no contrast
+        let gray = [128, 128, 128, 255].repeat(10 * 10);
+        let image = Image::new(&gray, 10, 10, PixelFormat::Rgba);
+        let weights = ImageWeights::new()
+            .binarize(Binarize::Dither)
+            .scheme(WeightScheme::Contrast)
+            .weights(&image, version);
+        let dark = weights.iter().filter(|w| w.value()).count();
+        assert!(dark.abs_diff(width * width / 2) <= width);
+        assert!(weights.iter().all(|w| w.weight() == 0));
+
+        let center = ImageWeights::new()
+            .scheme(WeightScheme::Center)
+            .weights(&image, version);
+        assert_eq!(center[width * 10 + 10].weight(), 127);
+        assert_eq!(center[0].weight(), 1);
+    }
+
+    #[test]
+    fn generate_qart_image_works() {
+        let input = "https://github.com/zhengkyl/fuqr";
+        // dark circle on white
+        let pixels: Vec<u8> = (0..100 * 100)
+            .map(|i| {
+                let (x, y) = (i % 100, i / 100);
+                if (x - 50) * (x - 50) + (y - 50) * (y - 50) < 30 * 30 {
+                    0
+                } else {
+                    255
+                }
+            })
+            .collect();
+        let image = Image::new(&pixels, 100, 100, PixelFormat::Luma);
+        let options = QrOptions::new().min_version(Version(5));
+        let image_weights = ImageWeights::new().scheme(WeightScheme::Center);
+
+        let qr_code = generate_qart_image(input, &options, &image, &image_weights).unwrap();
+        assert_eq!(decode(&qr_code.matrix).unwrap().text(), input);
+
+        let invalid = Image {
+            pixels: &pixels[1..],
+            ..image
+        };
+        assert_eq!(
+            generate_qart_image(input, &options, &invalid, &image_weights).unwrap_err(),
+            QartError::InvalidPixelWeights
+        );
+    }
+}